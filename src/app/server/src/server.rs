@@ -1,23 +1,172 @@
-use openai::chat;
-use actix_web::{get, App, HttpResponse, HttpServer, Responder, web};
+use std::collections::HashMap;
 
+use actix_web::{delete, get, post, web, App, HttpResponse, HttpServer, Responder};
+
+use openai::chat::Message;
+use openai::conversation::Conversation;
+use openai::provider;
+use openai::store::{ConversationStore, StoredConversation};
+use openai::templates::TemplateLibrary;
+use openai::{ChatProvider, OpenAiError};
 use types::AppState;
 
-async fn p() -> String {
-    let res = chat::prompt(
+async fn p(provider: &dyn ChatProvider) -> String {
+    let res = provider.prompt(
             String::from("MARCO!"),
             vec![]   
     ).await;
 
     match res {
         Ok(msg) => return format!("Response: {}", msg.content),
-        Err(e) => return format!("Error: {}", e)
+        Err(e) => return describe_error(e)
+    }
+}
+
+fn describe_error(e: OpenAiError) -> String {
+    match e {
+        OpenAiError::MissingApiKey => String::from("Error: the server has no API key configured for its LLM provider"),
+        OpenAiError::Config(msg) => format!("Error: the LLM provider is misconfigured: {}", msg),
+        OpenAiError::ContentFlagged { categories, .. } => {
+            format!("Error: refused by the content filter ({})", categories.join(", "))
+        }
+        OpenAiError::RateLimited { .. } => String::from("Error: rate limited by the LLM provider, try again shortly"),
+        e => format!("Error: {}", e)
     }
 }
 
 #[get("/")]
 async fn hello() -> impl Responder {
-    HttpResponse::Ok().body(p().await)
+    let body = match provider::default_provider() {
+        Ok(provider) => p(provider).await,
+        Err(e) => describe_error(e),
+    };
+    HttpResponse::Ok().body(body)
+}
+
+// Sends text to a saved conversation, or a new one when no id is given, and saves the reply.
+async fn converse(
+    provider: &dyn ChatProvider,
+    store: &ConversationStore,
+    id: Option<&str>,
+    text: String,
+) -> Result<StoredConversation, OpenAiError> {
+    let mut stored = match id {
+        Some(id) => store.load(id)?,
+        None => store.create(Conversation::new(provider.default_model(), None)),
+    };
+    stored.conversation.send(provider, text).await?;
+    store.save(&mut stored)?;
+    Ok(stored)
+}
+
+fn error_response(e: OpenAiError) -> HttpResponse {
+    match &e {
+        OpenAiError::Storage(io) if io.kind() == std::io::ErrorKind::NotFound => {
+            HttpResponse::NotFound().body("Error: no such conversation")
+        }
+        OpenAiError::Storage(io) if io.kind() == std::io::ErrorKind::InvalidInput => {
+            HttpResponse::BadRequest().body(describe_error(e))
+        }
+        OpenAiError::ContentFlagged { .. } => HttpResponse::UnprocessableEntity().body(describe_error(e)),
+        OpenAiError::MissingVariables { .. } | OpenAiError::InvalidRequest(_) => {
+            HttpResponse::BadRequest().body(describe_error(e))
+        }
+        _ => HttpResponse::InternalServerError().body(describe_error(e)),
+    }
+}
+
+#[get("/conversations")]
+async fn list_conversations(store: web::Data<ConversationStore>) -> impl Responder {
+    match store.list() {
+        Ok(conversations) => HttpResponse::Ok().json(conversations),
+        Err(e) => error_response(e),
+    }
+}
+
+#[get("/conversations/{id}")]
+async fn get_conversation(store: web::Data<ConversationStore>, id: web::Path<String>) -> impl Responder {
+    match store.load(&id) {
+        Ok(stored) => HttpResponse::Ok().json(stored),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/conversations")]
+async fn start_conversation(store: web::Data<ConversationStore>, text: String) -> impl Responder {
+    let result = match provider::default_provider() {
+        Ok(provider) => converse(provider, &store, None, text).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(stored) => HttpResponse::Created().json(stored),
+        Err(e) => error_response(e),
+    }
+}
+
+#[post("/conversations/{id}")]
+async fn continue_conversation(
+    store: web::Data<ConversationStore>,
+    id: web::Path<String>,
+    text: String,
+) -> impl Responder {
+    let result = match provider::default_provider() {
+        Ok(provider) => converse(provider, &store, Some(&id), text).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(stored) => HttpResponse::Ok().json(stored),
+        Err(e) => error_response(e),
+    }
+}
+
+#[delete("/conversations/{id}")]
+async fn delete_conversation(store: web::Data<ConversationStore>, id: web::Path<String>) -> impl Responder {
+    match store.delete(&id) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => error_response(e),
+    }
+}
+
+// Renders a named template with the given variables and asks the model.
+async fn run_template(
+    provider: &dyn ChatProvider,
+    library: &TemplateLibrary,
+    name: &str,
+    vars: &HashMap<String, String>,
+) -> Result<Message, OpenAiError> {
+    let payload = library
+        .get(name)?
+        .payload(vars)?
+        .default_model(provider.default_model())
+        .build()?;
+    provider.complete(payload).await?.into_message()
+}
+
+#[get("/templates")]
+async fn list_templates(library: web::Data<TemplateLibrary>) -> impl Responder {
+    HttpResponse::Ok().json(library.list())
+}
+
+#[post("/templates/{name}")]
+async fn invoke_template(
+    library: web::Data<TemplateLibrary>,
+    name: web::Path<String>,
+    vars: web::Json<HashMap<String, String>>,
+) -> impl Responder {
+    let result = match provider::default_provider() {
+        Ok(provider) => run_template(provider, &library, &name, &vars).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(message) => HttpResponse::Ok().json(message),
+        Err(e) => error_response(e),
+    }
+}
+
+// Tokens and cost of every LLM call this server has made, per model.
+#[get("/usage")]
+async fn usage_report() -> impl Responder {
+    HttpResponse::Ok().json(openai::usage::report())
 }
 
 // struct Counter {
@@ -39,15 +188,91 @@ pub async fn start_server() -> std::io::Result<()> {
         title: String::from("Oxygen"),
         ..Default::default()
     });
+    let store = web::Data::new(ConversationStore::from_env());
+    let templates = match TemplateLibrary::from_env() {
+        Ok(library) => web::Data::new(library),
+        Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())),
+    };
 
     HttpServer::new(move || {
         // move counter into the closure
         App::new()
         .app_data(app_state.clone()) // <- register the created data
+        .app_data(store.clone())
+        .app_data(templates.clone())
+        .service(list_templates)
+        .service(invoke_template)
+        .service(list_conversations)
+        .service(get_conversation)
+        .service(start_conversation)
+        .service(continue_conversation)
+        .service(delete_conversation)
+        .service(usage_report)
         // .service(hello)
         .route("/", web::get().to(index))
     })
     .bind(("127.0.0.1", 7878))?
     .run()
     .await
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai::mock::{MockResponse, MockServer, TempPath};
+
+    #[actix_web::test]
+    async fn p_returns_the_model_reply() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat("POLO!"));
+
+        assert_eq!(p(&server.client()).await, "Response: POLO!");
+        assert_eq!(server.requests()[0].json()["messages"][0]["content"], "MARCO!");
+    }
+
+    #[actix_web::test]
+    async fn p_explains_rate_limits() {
+        let server = MockServer::start();
+        for _ in 0..3 {
+            server.enqueue(MockResponse::rate_limited(0));
+        }
+
+        assert_eq!(p(&server.client()).await, "Error: rate limited by the LLM provider, try again shortly");
+    }
+
+    #[actix_web::test]
+    async fn converse_continues_a_saved_conversation() {
+        let server = MockServer::start();
+        let dir = TempPath::new("server-conversations");
+        let store = ConversationStore::new(&dir);
+
+        // a failed first reply leaves nothing on disk
+        server.enqueue(MockResponse::error(400, "invalid_request_error", "invalid_value", "Bad request"));
+        assert!(converse(&server.client(), &store, None, String::from("MARCO!")).await.is_err());
+        assert!(store.list().unwrap().is_empty());
+
+        server.enqueue(MockResponse::chat("POLO!"));
+        let started = converse(&server.client(), &store, None, String::from("MARCO!")).await.unwrap();
+
+        server.enqueue(MockResponse::chat("Still POLO!"));
+        let continued = converse(&server.client(), &store, Some(&started.id), String::from("Again?")).await.unwrap();
+
+        assert_eq!(continued.id, started.id);
+        assert_eq!(store.load(&started.id).unwrap().conversation.messages.len(), 4);
+        assert_eq!(server.requests()[2].json()["messages"][1]["content"], "POLO!");
+    }
+
+    #[actix_web::test]
+    async fn run_template_renders_and_sends_the_prompt() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat("It means the file is missing."));
+        let vars = HashMap::from([(String::from("input"), String::from("ENOENT: no such file"))]);
+
+        let reply = run_template(&server.client(), &TemplateLibrary::builtin(), "explain-error", &vars).await.unwrap();
+
+        assert_eq!(reply.content, "It means the file is missing.");
+        let body = server.requests()[0].json();
+        assert_eq!(body["messages"][1]["content"], "I got this error:\n\nENOENT: no such file");
+    }
+}
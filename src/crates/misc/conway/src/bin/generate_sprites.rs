@@ -0,0 +1,39 @@
+// Regenerates the cell sprites with the OpenAI image API, replacing the files in assets/sprites.
+//
+//   cargo run -p conway --bin generate_sprites -- [style]
+//
+// e.g. `-- "neon pixel art"`. Needs OPENAI_API_KEY.
+use openai::images::{ImageRequest, ImageSize};
+use std::path::Path;
+
+const SPRITES_DIR: &str = "assets/sprites";
+const DEFAULT_STYLE: &str = "flat minimalist game art";
+
+#[tokio::main]
+async fn main() {
+    let style = std::env::args().nth(1).unwrap_or_else(|| DEFAULT_STYLE.to_string());
+    let sprites = Path::new(env!("CARGO_MANIFEST_DIR")).join(SPRITES_DIR);
+
+    let cells = [
+        ("alive_cell.png", "a bright, glowing, living cell"),
+        ("dead_cell.png", "an empty, dark, inactive cell"),
+    ];
+    for (file, subject) in cells {
+        let mut request = ImageRequest::new(format!(
+            "A square tile for a Conway's Game of Life grid showing {}, {}, filling the whole tile, no text",
+            subject, style
+        ));
+        // the grid draws sprites at about 252px, so the smallest size is plenty
+        request.model = "dall-e-2".to_string();
+        request.size = Some(ImageSize::Small);
+
+        let path = sprites.join(file);
+        match openai::images::generate_image_to_file(&request, &path).await {
+            Ok(_) => println!("Wrote {}", path.display()),
+            Err(e) => {
+                eprintln!("Failed to generate {}: {}", file, e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use async_trait::async_trait;
+use reqwest::Method;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+
+use crate::chat::{
+    ChatChunk, ChatCompletion, ChatModel, ChatStream, Choice, ChunkChoice, Delta, FinishReason, FunctionCallDelta,
+    Message, Payload, Role, ToolCallDelta, Usage,
+};
+use crate::client::{AuthScheme, ClientConfig, OpenAiClient};
+use crate::content::{Content, ContentPart};
+use crate::error::OpenAiError;
+use crate::provider::ChatProvider;
+use crate::tools::{FunctionCall, ToolCall, ToolChoice};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+pub const DEFAULT_MODEL: &str = "claude-3-5-sonnet-latest";
+pub const API_VERSION: &str = "2023-06-01";
+// the messages API requires max_tokens, OpenAI's doesn't
+const DEFAULT_MAX_TOKENS: i32 = 4096;
+
+// Anthropic's messages API behind the ChatProvider interface. Requests are translated from
+// OpenAI's shape and responses back to it, so callers don't see the difference.
+#[derive(Debug, Clone)]
+pub struct AnthropicClient {
+    client: OpenAiClient,
+    model: ChatModel,
+}
+
+impl AnthropicClient {
+    pub fn new(api_key: &str, model: ChatModel) -> Result<AnthropicClient, OpenAiError> {
+        AnthropicClient::with_config(AnthropicClient::config(DEFAULT_BASE_URL, api_key), model)
+    }
+
+    // The ClientConfig for the API: key in `x-api-key` and the pinned API version.
+    pub fn config(base_url: &str, api_key: &str) -> ClientConfig {
+        ClientConfig {
+            base_url: base_url.to_string(),
+            api_key: Some(api_key.to_string()),
+            auth: AuthScheme::Header("x-api-key".to_string()),
+            headers: vec![("anthropic-version".to_string(), API_VERSION.to_string())],
+            ..Default::default()
+        }
+    }
+
+    pub fn with_config(config: ClientConfig, model: ChatModel) -> Result<AnthropicClient, OpenAiError> {
+        Ok(AnthropicClient {
+            client: OpenAiClient::new(config)?,
+            model,
+        })
+    }
+
+    // Reads ANTHROPIC_API_KEY, ANTHROPIC_BASE_URL and ANTHROPIC_MODEL.
+    pub fn from_env() -> Result<AnthropicClient, OpenAiError> {
+        let api_key = env::var("ANTHROPIC_API_KEY")
+            .map_err(|_| OpenAiError::Config("ANTHROPIC_API_KEY not set".to_string()))?;
+        let base_url = env::var("ANTHROPIC_BASE_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_string());
+        let model = env::var("ANTHROPIC_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.to_string());
+        AnthropicClient::with_config(AnthropicClient::config(&base_url, &api_key), ChatModel::from(model.as_str()))
+    }
+}
+
+#[async_trait]
+impl ChatProvider for AnthropicClient {
+    fn name(&self) -> &str {
+        "anthropic"
+    }
+
+    fn default_model(&self) -> ChatModel {
+        self.model.clone()
+    }
+
+    async fn complete(&self, payload: Payload) -> Result<ChatCompletion, OpenAiError> {
+        let request = self.client.request(Method::POST, "messages").json(&to_request(&payload, false));
+        let body = self.client.send(request).await?.text().await?;
+
+        let response = serde_json::from_str::<MessagesResponse>(&body)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing response body: {}", e)))?;
+        let completion = response.into_completion();
+        completion.track_usage();
+        Ok(completion)
+    }
+
+    async fn stream(&self, payload: Payload) -> Result<ChatStream, OpenAiError> {
+        let request = self.client.request(Method::POST, "messages").json(&to_request(&payload, true));
+        let response = self.client.send(request).await?;
+
+        let mut translator = StreamTranslator::default();
+        Ok(ChatStream::with_parser(response, Box::new(move |data| translator.translate(data))))
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, OpenAiError> {
+        #[derive(Deserialize)]
+        struct ModelList {
+            data: Vec<ModelEntry>,
+        }
+        #[derive(Deserialize)]
+        struct ModelEntry {
+            id: String,
+        }
+
+        let request = self.client.request(Method::GET, "models");
+        let body = self.client.send(request).await?.text().await?;
+        let list = serde_json::from_str::<ModelList>(&body)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing model list: {}", e)))?;
+        Ok(list.data.into_iter().map(|model| model.id).collect())
+    }
+}
+
+// Builds a messages API request body from a chat payload.
+pub fn to_request(payload: &Payload, stream: bool) -> Value {
+    let system: Vec<String> = payload
+        .messages
+        .iter()
+        .filter(|message| message.role == Role::System)
+        .map(|message| message.content.text())
+        .collect();
+
+    // the API wants strictly alternating turns, so consecutive messages from the same side
+    // (e.g. several tool results) are merged into one
+    let mut messages: Vec<(&str, Vec<Value>)> = vec![];
+    for message in payload.messages.iter().filter(|message| message.role != Role::System) {
+        let role = if message.role == Role::Assistant { "assistant" } else { "user" };
+        let blocks = content_blocks(message);
+        match messages.last_mut() {
+            Some((last, existing)) if *last == role => existing.extend(blocks),
+            _ => messages.push((role, blocks)),
+        }
+    }
+
+    let mut body = json!({
+        "model": payload.model,
+        "max_tokens": payload.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+        "messages": messages
+            .into_iter()
+            .map(|(role, content)| json!({ "role": role, "content": content }))
+            .collect::<Vec<_>>(),
+    });
+    if !system.is_empty() {
+        body["system"] = json!(system.join("\n\n"));
+    }
+    if let Some(temperature) = payload.temperature {
+        body["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = payload.top_p {
+        body["top_p"] = json!(top_p);
+    }
+    if let Some(stop) = &payload.stop {
+        body["stop_sequences"] = json!(stop);
+    }
+    if let Some(tools) = &payload.tools {
+        body["tools"] = tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "name": tool.function.name,
+                    "description": tool.function.description,
+                    "input_schema": tool.function.parameters,
+                })
+            })
+            .collect();
+    }
+    match &payload.tool_choice {
+        Some(ToolChoice::Auto) => body["tool_choice"] = json!({ "type": "auto" }),
+        Some(ToolChoice::Required) => body["tool_choice"] = json!({ "type": "any" }),
+        Some(ToolChoice::Function(name)) => body["tool_choice"] = json!({ "type": "tool", "name": name }),
+        // there is no "none"; leaving the tools out has the same effect
+        Some(ToolChoice::None) => {
+            if let Some(object) = body.as_object_mut() {
+                object.remove("tools");
+            }
+        }
+        None => {}
+    }
+    if stream {
+        body["stream"] = json!(true);
+    }
+    body
+}
+
+fn content_blocks(message: &Message) -> Vec<Value> {
+    if message.role == Role::Tool {
+        return vec![json!({
+            "type": "tool_result",
+            "tool_use_id": message.tool_call_id,
+            "content": message.content.text(),
+        })];
+    }
+
+    let mut blocks = match &message.content {
+        Content::Text(text) if text.is_empty() => vec![],
+        Content::Text(text) => vec![json!({ "type": "text", "text": text })],
+        Content::Parts(parts) => parts.iter().map(part_block).collect(),
+    };
+    for call in message.tool_calls.iter().flatten() {
+        let input = serde_json::from_str::<Value>(&call.function.arguments).unwrap_or_else(|_| json!({}));
+        blocks.push(json!({ "type": "tool_use", "id": call.id, "name": call.function.name, "input": input }));
+    }
+    blocks
+}
+
+fn part_block(part: &ContentPart) -> Value {
+    match part {
+        ContentPart::Text { text } => json!({ "type": "text", "text": text }),
+        ContentPart::ImageUrl { image_url } => {
+            // data urls look like `data:image/png;base64,<data>`
+            let inline = image_url
+                .url
+                .strip_prefix("data:")
+                .and_then(|rest| rest.split_once(";base64,"));
+            match inline {
+                Some((media_type, data)) => json!({
+                    "type": "image",
+                    "source": { "type": "base64", "media_type": media_type, "data": data },
+                }),
+                None => json!({ "type": "image", "source": { "type": "url", "url": image_url.url } }),
+            }
+        }
+    }
+}
+
+fn finish_reason(stop_reason: &str) -> FinishReason {
+    match stop_reason {
+        "end_turn" | "stop_sequence" => FinishReason::Stop,
+        "max_tokens" => FinishReason::Length,
+        "tool_use" => FinishReason::ToolCalls,
+        _ => FinishReason::Other,
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct MessagesResponse {
+    id: String,
+    model: ChatModel,
+    content: Vec<ResponseBlock>,
+    stop_reason: Option<String>,
+    usage: Option<MessagesUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseBlock {
+    Text { text: String },
+    ToolUse { id: String, name: String, input: Value },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+struct MessagesUsage {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
+
+impl From<MessagesUsage> for Usage {
+    fn from(usage: MessagesUsage) -> Self {
+        Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: usage.output_tokens,
+            total_tokens: usage.input_tokens + usage.output_tokens,
+        }
+    }
+}
+
+impl MessagesResponse {
+    fn into_completion(self) -> ChatCompletion {
+        let mut text = String::new();
+        let mut tool_calls = vec![];
+        for block in self.content {
+            match block {
+                ResponseBlock::Text { text: part } => text.push_str(&part),
+                ResponseBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                    id,
+                    kind: "function".to_string(),
+                    function: FunctionCall { name, arguments: input.to_string() },
+                }),
+                ResponseBlock::Other => {}
+            }
+        }
+
+        ChatCompletion {
+            id: self.id,
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: self.model,
+            choices: vec![Choice {
+                index: 0,
+                message: Message {
+                    role: Role::Assistant,
+                    content: Content::Text(text),
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                    tool_call_id: None,
+                },
+                finish_reason: self.stop_reason.as_deref().map(finish_reason),
+                logprobs: None,
+            }],
+            usage: self.usage.map(Usage::from),
+            system_fingerprint: None,
+        }
+    }
+}
+
+// Maps streaming events onto OpenAI-style chunks. Tool calls are numbered in the order
+// they start, the way OpenAI numbers them, rather than by content block.
+#[derive(Default)]
+struct StreamTranslator {
+    tool_blocks: HashMap<usize, usize>,
+    model: Option<ChatModel>,
+    // reported by message_start; the output count comes with message_delta
+    input_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart { message: StartMessage },
+    ContentBlockStart { index: usize, content_block: StartBlock },
+    ContentBlockDelta { index: usize, delta: BlockDelta },
+    MessageDelta {
+        delta: MessageDelta,
+        #[serde(default)]
+        usage: Option<MessagesUsage>,
+    },
+    Error { error: crate::error::ApiError },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StartBlock {
+    ToolUse { id: String, name: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta { text: String },
+    InputJsonDelta { partial_json: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+struct StartMessage {
+    #[serde(default)]
+    model: Option<ChatModel>,
+    #[serde(default)]
+    usage: Option<MessagesUsage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MessageDelta {
+    stop_reason: Option<String>,
+}
+
+impl StreamTranslator {
+    fn translate(&mut self, data: &str) -> Result<Option<ChatChunk>, OpenAiError> {
+        let event = serde_json::from_str::<StreamEvent>(data)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing stream event: {}", e)))?;
+
+        let delta = match event {
+            StreamEvent::MessageStart { message } => {
+                self.model = message.model;
+                self.input_tokens = message.usage.unwrap_or_default().input_tokens;
+                Delta {
+                    role: Some(Role::Assistant),
+                    ..Default::default()
+                }
+            }
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block: StartBlock::ToolUse { id, name },
+            } => {
+                let tool_index = self.tool_blocks.len();
+                self.tool_blocks.insert(index, tool_index);
+                Delta {
+                    tool_calls: Some(vec![ToolCallDelta {
+                        index: tool_index,
+                        id: Some(id),
+                        function: Some(FunctionCallDelta { name: Some(name), arguments: None }),
+                    }]),
+                    ..Default::default()
+                }
+            }
+            StreamEvent::ContentBlockDelta { delta: BlockDelta::TextDelta { text }, .. } => Delta {
+                content: Some(text),
+                ..Default::default()
+            },
+            StreamEvent::ContentBlockDelta {
+                index,
+                delta: BlockDelta::InputJsonDelta { partial_json },
+            } => match self.tool_blocks.get(&index) {
+                Some(&tool_index) => Delta {
+                    tool_calls: Some(vec![ToolCallDelta {
+                        index: tool_index,
+                        id: None,
+                        function: Some(FunctionCallDelta { name: None, arguments: Some(partial_json) }),
+                    }]),
+                    ..Default::default()
+                },
+                None => return Ok(None),
+            },
+            StreamEvent::MessageDelta { delta, usage } => {
+                return Ok(Some(ChatChunk {
+                    id: None,
+                    model: self.model.clone(),
+                    choices: vec![ChunkChoice {
+                        index: 0,
+                        delta: Delta::default(),
+                        finish_reason: delta.stop_reason.as_deref().map(finish_reason),
+                    }],
+                    usage: usage.map(|usage| Usage::from(MessagesUsage {
+                        input_tokens: self.input_tokens,
+                        output_tokens: usage.output_tokens,
+                    })),
+                }))
+            }
+            StreamEvent::Error { error } => return Err(OpenAiError::Api { status: 200, error }),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(ChatChunk {
+            id: None,
+            choices: vec![ChunkChoice { index: 0, delta, finish_reason: None }],
+            ..Default::default()
+        }))
+    }
+}
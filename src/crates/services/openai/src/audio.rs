@@ -0,0 +1,291 @@
+use futures_util::StreamExt;
+use reqwest::multipart::{Form, Part};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+use crate::chat::check_range;
+use crate::client::{default_client, OpenAiClient};
+use crate::error::OpenAiError;
+
+pub const DEFAULT_TRANSCRIPTION_MODEL: &str = "whisper-1";
+pub const DEFAULT_SPEECH_MODEL: &str = "tts-1";
+
+// The API refuses longer speech input.
+pub const MAX_SPEECH_INPUT: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TranscriptFormat {
+    #[default]
+    Json,
+    Text,
+    Srt,
+    // the only format with language, duration and timestamps
+    VerboseJson,
+    Vtt,
+}
+
+impl fmt::Display for TranscriptFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TranscriptFormat::Json => "json",
+            TranscriptFormat::Text => "text",
+            TranscriptFormat::Srt => "srt",
+            TranscriptFormat::VerboseJson => "verbose_json",
+            TranscriptFormat::Vtt => "vtt",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampGranularity {
+    Word,
+    Segment,
+}
+
+impl fmt::Display for TimestampGranularity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampGranularity::Word => write!(f, "word"),
+            TimestampGranularity::Segment => write!(f, "segment"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TranscriptionRequest {
+    // a local recording in one of the formats the API accepts, e.g. mp3, m4a, wav or webm
+    pub file: PathBuf,
+    pub model: String,
+    // ISO-639-1 code like "en"; improves accuracy and latency when known
+    pub language: Option<String>,
+    // earlier text or spellings of unusual words, to steer the transcript
+    pub prompt: Option<String>,
+    pub response_format: Option<TranscriptFormat>,
+    pub temperature: Option<f32>,
+    pub timestamp_granularities: Vec<TimestampGranularity>,
+}
+
+impl TranscriptionRequest {
+    pub fn new<P: Into<PathBuf>>(file: P) -> TranscriptionRequest {
+        TranscriptionRequest {
+            file: file.into(),
+            model: DEFAULT_TRANSCRIPTION_MODEL.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // Asks for word and segment timings, which come with the verbose format.
+    pub fn with_timestamps(mut self) -> TranscriptionRequest {
+        self.response_format = Some(TranscriptFormat::VerboseJson);
+        self.timestamp_granularities = vec![TimestampGranularity::Word, TimestampGranularity::Segment];
+        self
+    }
+
+    fn validate(&self) -> Result<(), OpenAiError> {
+        check_range("temperature", self.temperature, 0.0, 1.0)?;
+        if !self.timestamp_granularities.is_empty() && self.response_format != Some(TranscriptFormat::VerboseJson) {
+            return Err(OpenAiError::InvalidRequest(
+                "timestamp_granularities needs the verbose_json response format".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    async fn form(&self) -> Result<Form, OpenAiError> {
+        let data = tokio::fs::read(&self.file).await.map_err(OpenAiError::Storage)?;
+        let file_name = self
+            .file
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "audio".to_string());
+
+        let mut form = Form::new()
+            .part("file", Part::bytes(data).file_name(file_name))
+            .text("model", self.model.clone());
+        if let Some(language) = &self.language {
+            form = form.text("language", language.clone());
+        }
+        if let Some(prompt) = &self.prompt {
+            form = form.text("prompt", prompt.clone());
+        }
+        if let Some(format) = self.response_format {
+            form = form.text("response_format", format.to_string());
+        }
+        if let Some(temperature) = self.temperature {
+            form = form.text("temperature", temperature.to_string());
+        }
+        for granularity in &self.timestamp_granularities {
+            form = form.text("timestamp_granularities[]", granularity.to_string());
+        }
+        Ok(form)
+    }
+}
+
+// What was said. Only the verbose format fills in more than the text; the text, srt and vtt
+// formats put the whole response body in text.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Transcription {
+    pub text: String,
+    #[serde(default)]
+    pub language: Option<String>,
+    // seconds
+    #[serde(default)]
+    pub duration: Option<f64>,
+    #[serde(default)]
+    pub words: Vec<TranscriptWord>,
+    #[serde(default)]
+    pub segments: Vec<TranscriptSegment>,
+}
+
+// Times are in seconds from the start of the recording.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TranscriptWord {
+    pub word: String,
+    pub start: f64,
+    pub end: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TranscriptSegment {
+    pub id: u32,
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    #[serde(default)]
+    pub avg_logprob: Option<f64>,
+    // likelihood the segment is silence or noise rather than speech
+    #[serde(default)]
+    pub no_speech_prob: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Voice {
+    #[default]
+    Alloy,
+    Echo,
+    Fable,
+    Onyx,
+    Nova,
+    Shimmer,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SpeechFormat {
+    #[default]
+    Mp3,
+    Opus,
+    Aac,
+    Flac,
+    Wav,
+    // raw 24kHz 16-bit little-endian samples, no header
+    Pcm,
+}
+
+impl SpeechFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            SpeechFormat::Mp3 => "mp3",
+            SpeechFormat::Opus => "opus",
+            SpeechFormat::Aac => "aac",
+            SpeechFormat::Flac => "flac",
+            SpeechFormat::Wav => "wav",
+            SpeechFormat::Pcm => "pcm",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct SpeechRequest {
+    pub model: String,
+    pub input: String,
+    pub voice: Voice,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<SpeechFormat>,
+    // 0.25 to 4.0, 1.0 when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f32>,
+}
+
+impl SpeechRequest {
+    pub fn new<S: Into<String>>(input: S, voice: Voice) -> SpeechRequest {
+        SpeechRequest {
+            model: DEFAULT_SPEECH_MODEL.to_string(),
+            input: input.into(),
+            voice,
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), OpenAiError> {
+        check_range("speed", self.speed, 0.25, 4.0)?;
+        let length = self.input.chars().count();
+        if length > MAX_SPEECH_INPUT {
+            return Err(OpenAiError::InvalidRequest(format!(
+                "speech input must be at most {} characters, got {}",
+                MAX_SPEECH_INPUT, length
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl OpenAiClient {
+    pub async fn transcribe(&self, request: &TranscriptionRequest) -> Result<Transcription, OpenAiError> {
+        request.validate()?;
+        let response = self.send_multipart("audio/transcriptions", request.form().await?).await?;
+        let body = response.text().await?;
+
+        match request.response_format.unwrap_or_default() {
+            TranscriptFormat::Json | TranscriptFormat::VerboseJson => serde_json::from_str::<Transcription>(&body)
+                .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing transcription: {}", e))),
+            _ => Ok(Transcription {
+                text: body,
+                ..Default::default()
+            }),
+        }
+    }
+
+    // The whole recording in memory; use speech_to_file for long input.
+    pub async fn speech(&self, request: &SpeechRequest) -> Result<Vec<u8>, OpenAiError> {
+        request.validate()?;
+        let http_request = self.request(Method::POST, "audio/speech").json(request);
+        Ok(self.send(http_request).await?.bytes().await?.to_vec())
+    }
+
+    // Writes the audio to the file as it arrives and returns the number of bytes written.
+    pub async fn speech_to_file<P: AsRef<Path>>(&self, request: &SpeechRequest, path: P) -> Result<u64, OpenAiError> {
+        request.validate()?;
+        let http_request = self.request(Method::POST, "audio/speech").json(request);
+        let response = self.send(http_request).await?;
+
+        if let Some(dir) = path.as_ref().parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(OpenAiError::Storage)?;
+        }
+        let mut file = tokio::fs::File::create(path).await.map_err(OpenAiError::Storage)?;
+        let mut written = 0;
+        let mut body = response.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            file.write_all(&chunk).await.map_err(OpenAiError::Storage)?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await.map_err(OpenAiError::Storage)?;
+        Ok(written)
+    }
+}
+
+pub async fn transcribe(request: &TranscriptionRequest) -> Result<Transcription, OpenAiError> {
+    default_client()?.transcribe(request).await
+}
+
+pub async fn speech_to_file<P: AsRef<Path>>(request: &SpeechRequest, path: P) -> Result<u64, OpenAiError> {
+    default_client()?.speech_to_file(request, path).await
+}
@@ -0,0 +1,398 @@
+use reqwest::multipart::{Form, Part};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::chat::{ChatCompletion, Payload};
+use crate::client::{default_client, OpenAiClient};
+use crate::completions::{CompletionRequest, CompletionResponse, Endpoint};
+use crate::error::{ApiError, OpenAiError};
+
+// The only window the API offers; most batches finish well within it.
+pub const COMPLETION_WINDOW: &str = "24h";
+// Per batch limit on the number of requests.
+pub const MAX_BATCH_REQUESTS: usize = 50_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileObject {
+    pub id: String,
+    #[serde(default)]
+    pub bytes: u64,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub filename: String,
+    #[serde(default)]
+    pub purpose: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+struct BatchLine {
+    custom_id: String,
+    method: &'static str,
+    url: &'static str,
+    body: Value,
+}
+
+// The requests of one batch, each under a caller-chosen custom_id that its result comes
+// back with. A batch goes to a single endpoint, so chat and instruct models can't be mixed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BatchInput {
+    lines: Vec<BatchLine>,
+    ids: HashSet<String>,
+}
+
+impl BatchInput {
+    pub fn new() -> BatchInput {
+        BatchInput::default()
+    }
+
+    // Numbers the payloads as request-0, request-1, ...
+    pub fn from_payloads(payloads: Vec<Payload>) -> Result<BatchInput, OpenAiError> {
+        let mut input = BatchInput::new();
+        for (i, payload) in payloads.into_iter().enumerate() {
+            input.push(format!("request-{}", i), payload)?;
+        }
+        Ok(input)
+    }
+
+    pub fn push<S: Into<String>>(&mut self, custom_id: S, mut payload: Payload) -> Result<(), OpenAiError> {
+        let custom_id = custom_id.into();
+        if self.ids.contains(&custom_id) {
+            return Err(OpenAiError::InvalidRequest(format!("duplicate custom_id {:?}", custom_id)));
+        }
+        if self.lines.len() >= MAX_BATCH_REQUESTS {
+            return Err(OpenAiError::InvalidRequest(format!(
+                "a batch holds at most {} requests",
+                MAX_BATCH_REQUESTS
+            )));
+        }
+        let endpoint = payload.model.endpoint();
+        if let Some(first) = self.lines.first() {
+            if first.url != endpoint.path() {
+                return Err(OpenAiError::WrongEndpoint {
+                    model: payload.model.to_string(),
+                    endpoint: first.url,
+                });
+            }
+        }
+
+        // results are collected whole, so streaming makes no sense here
+        payload.stream = None;
+        payload.stream_options = None;
+        let body = match endpoint {
+            Endpoint::Chat => json!(payload),
+            Endpoint::Completions => json!(CompletionRequest::from_payload(&payload)?),
+        };
+        self.ids.insert(custom_id.clone());
+        self.lines.push(BatchLine {
+            custom_id,
+            method: "POST",
+            url: endpoint.path(),
+            body,
+        });
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    // In the order they were added.
+    pub fn ids(&self) -> Vec<&str> {
+        self.lines.iter().map(|line| line.custom_id.as_str()).collect()
+    }
+
+    pub fn endpoint(&self) -> Option<&'static str> {
+        self.lines.first().map(|line| line.url)
+    }
+
+    // One request per line, the format the Batch API reads.
+    pub fn to_jsonl(&self) -> String {
+        self.lines
+            .iter()
+            .map(|line| json!(line).to_string() + "\n")
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Validating,
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Expired,
+    Cancelling,
+    Cancelled,
+    // a status added to the API after this was written
+    #[serde(other)]
+    Unknown,
+}
+
+impl BatchStatus {
+    // No further changes will happen; expired and cancelled batches still have results for
+    // whatever finished in time.
+    pub fn is_done(self) -> bool {
+        matches!(
+            self,
+            BatchStatus::Failed | BatchStatus::Completed | BatchStatus::Expired | BatchStatus::Cancelled
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RequestCounts {
+    pub total: u32,
+    pub completed: u32,
+    pub failed: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct BatchErrors {
+    #[serde(default)]
+    pub data: Vec<ApiError>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub id: String,
+    pub endpoint: String,
+    pub status: BatchStatus,
+    pub input_file_id: String,
+    // set once some requests have finished, successfully or not
+    #[serde(default)]
+    pub output_file_id: Option<String>,
+    #[serde(default)]
+    pub error_file_id: Option<String>,
+    // why validation failed, for a batch that never started
+    #[serde(default)]
+    pub errors: Option<BatchErrors>,
+    #[serde(default)]
+    pub request_counts: Option<RequestCounts>,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub completed_at: Option<u64>,
+    #[serde(default)]
+    pub metadata: Option<BTreeMap<String, String>>,
+}
+
+// How often to check on a running batch: starting at initial, growing by multiplier up to
+// max. Batches take minutes to hours, so the defaults are patient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PollPolicy {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    // give up waiting after this long; the batch keeps running on the server
+    pub timeout: Option<Duration>,
+}
+
+impl Default for PollPolicy {
+    fn default() -> Self {
+        PollPolicy {
+            initial: Duration::from_secs(10),
+            max: Duration::from_secs(300),
+            multiplier: 1.5,
+            timeout: None,
+        }
+    }
+}
+
+impl PollPolicy {
+    pub fn delay(&self, poll: u32) -> Duration {
+        let exponent = poll.saturating_sub(1).min(32) as i32;
+        let delay = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        Duration::from_secs_f64(delay.min(self.max.as_secs_f64()))
+    }
+}
+
+#[derive(Deserialize)]
+struct OutputLine {
+    custom_id: String,
+    #[serde(default)]
+    response: Option<OutputResponse>,
+    #[serde(default)]
+    error: Option<ApiError>,
+}
+
+#[derive(Deserialize)]
+struct OutputResponse {
+    status_code: u16,
+    body: Value,
+}
+
+impl OutputLine {
+    fn into_result(self) -> (String, Result<ChatCompletion, OpenAiError>) {
+        let result = match (self.response, self.error) {
+            (Some(response), _) if response.status_code == 200 => parse_completion(response.body),
+            (Some(response), _) => Err(OpenAiError::Api {
+                status: response.status_code,
+                error: ApiError::from_body(&response.body.to_string()),
+            }),
+            (None, error) => Err(OpenAiError::BatchFailed {
+                id: self.custom_id.clone(),
+                errors: error.into_iter().collect(),
+            }),
+        };
+        (self.custom_id, result)
+    }
+}
+
+// Completions endpoint results are turned into the chat shape, like the direct calls do.
+fn parse_completion(body: Value) -> Result<ChatCompletion, OpenAiError> {
+    let parsed = if body["object"] == "text_completion" {
+        serde_json::from_value::<CompletionResponse>(body).map(CompletionResponse::into_chat)
+    } else {
+        serde_json::from_value::<ChatCompletion>(body)
+    };
+    parsed.map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing batch result: {}", e)))
+}
+
+// One request's outcome, matched back to its custom_id.
+#[derive(Debug)]
+pub struct BatchResult {
+    pub custom_id: String,
+    pub result: Result<ChatCompletion, OpenAiError>,
+}
+
+impl OpenAiClient {
+    pub async fn upload_file(&self, purpose: &str, file_name: &str, data: Vec<u8>) -> Result<FileObject, OpenAiError> {
+        let form = Form::new()
+            .text("purpose", purpose.to_string())
+            .part("file", Part::bytes(data).file_name(file_name.to_string()));
+        let body = self.send_multipart("files", form).await?.text().await?;
+
+        serde_json::from_str::<FileObject>(&body)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing file: {}", e)))
+    }
+
+    pub async fn file_content(&self, file_id: &str) -> Result<Vec<u8>, OpenAiError> {
+        let http_request = self.request(Method::GET, &format!("files/{}/content", file_id));
+        Ok(self.send(http_request).await?.bytes().await?.to_vec())
+    }
+
+    // Uploads the requests and starts a batch over them.
+    pub async fn submit_batch(&self, input: &BatchInput) -> Result<Batch, OpenAiError> {
+        let Some(endpoint) = input.endpoint() else {
+            return Err(OpenAiError::InvalidRequest("a batch needs at least one request".to_string()));
+        };
+        let file = self
+            .upload_file("batch", "batch.jsonl", input.to_jsonl().into_bytes())
+            .await?;
+
+        let body = json!({
+            "input_file_id": file.id,
+            "endpoint": endpoint,
+            "completion_window": COMPLETION_WINDOW,
+        });
+        let http_request = self.request(Method::POST, "batches").json(&body);
+        let body = self.send_with(http_request, false).await?.text().await?;
+        parse_batch(&body)
+    }
+
+    pub async fn batch(&self, batch_id: &str) -> Result<Batch, OpenAiError> {
+        let http_request = self.request(Method::GET, &format!("batches/{}", batch_id));
+        let body = self.send(http_request).await?.text().await?;
+        parse_batch(&body)
+    }
+
+    pub async fn cancel_batch(&self, batch_id: &str) -> Result<Batch, OpenAiError> {
+        let http_request = self.request(Method::POST, &format!("batches/{}/cancel", batch_id));
+        let body = self.send(http_request).await?.text().await?;
+        parse_batch(&body)
+    }
+
+    // Polls until the batch is done, or the policy's timeout passes; check the returned
+    // status to tell which.
+    pub async fn wait_for_batch(&self, batch_id: &str, policy: &PollPolicy) -> Result<Batch, OpenAiError> {
+        let started = Instant::now();
+        let mut poll = 1;
+        loop {
+            let batch = self.batch(batch_id).await?;
+            if batch.status.is_done() {
+                return Ok(batch);
+            }
+            let delay = policy.delay(poll);
+            if policy.timeout.is_some_and(|timeout| started.elapsed() + delay > timeout) {
+                return Ok(batch);
+            }
+            tokio::time::sleep(delay).await;
+            poll += 1;
+        }
+    }
+
+    // Every finished request's outcome by custom_id, from both the output and error files.
+    // Their usage is reported to the tracker each time this is called.
+    pub async fn batch_results(&self, batch: &Batch) -> Result<HashMap<String, Result<ChatCompletion, OpenAiError>>, OpenAiError> {
+        let mut results = HashMap::new();
+        for file_id in batch.output_file_id.iter().chain(&batch.error_file_id) {
+            let content = self.file_content(file_id).await?;
+            for line in String::from_utf8_lossy(&content).lines().filter(|line| !line.trim().is_empty()) {
+                let line = serde_json::from_str::<OutputLine>(line)
+                    .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing batch output: {}", e)))?;
+                let (custom_id, result) = line.into_result();
+                // billed at the batch discount, so the tracked cost is an upper bound
+                if let Ok(completion) = &result {
+                    completion.track_usage();
+                }
+                results.insert(custom_id, result);
+            }
+        }
+        Ok(results)
+    }
+
+    // Submits, waits and collects, returning one result per request in input order. A batch
+    // that failed validation or never finished is an error as a whole.
+    pub async fn run_batch(&self, input: &BatchInput, policy: &PollPolicy) -> Result<Vec<BatchResult>, OpenAiError> {
+        let batch = self.submit_batch(input).await?;
+        let batch = self.wait_for_batch(&batch.id, policy).await?;
+        if batch.status == BatchStatus::Failed || !batch.status.is_done() {
+            return Err(OpenAiError::BatchFailed {
+                id: batch.id,
+                errors: match batch.errors {
+                    Some(errors) => errors.data,
+                    None => vec![ApiError {
+                        message: format!("batch is {:?}", batch.status),
+                        ..Default::default()
+                    }],
+                },
+            });
+        }
+
+        let mut results = self.batch_results(&batch).await?;
+        Ok(input
+            .ids()
+            .into_iter()
+            .map(|id| BatchResult {
+                custom_id: id.to_string(),
+                result: results.remove(id).unwrap_or_else(|| {
+                    Err(OpenAiError::BatchFailed {
+                        id: id.to_string(),
+                        errors: vec![ApiError {
+                            message: format!("no result before the batch was {:?}", batch.status),
+                            ..Default::default()
+                        }],
+                    })
+                }),
+            })
+            .collect())
+    }
+}
+
+fn parse_batch(body: &str) -> Result<Batch, OpenAiError> {
+    serde_json::from_str::<Batch>(body).map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing batch: {}", e)))
+}
+
+pub async fn run_batch(input: &BatchInput, policy: &PollPolicy) -> Result<Vec<BatchResult>, OpenAiError> {
+    default_client()?.run_batch(input, policy).await
+}
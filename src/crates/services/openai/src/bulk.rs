@@ -0,0 +1,175 @@
+use futures_util::stream::{self, StreamExt};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::chat::{ChatCompletion, Message, Payload};
+use crate::conversation::estimate_message_tokens;
+use crate::error::OpenAiError;
+use crate::provider::ChatProvider;
+
+// Client-side limits for sending many requests. Match them to the account's tier so the
+// batch is throttled here instead of by 429s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    pub max_concurrency: usize,
+    pub requests_per_minute: Option<u32>,
+    // counted from the prompt estimate plus max_tokens, then corrected with the reported usage
+    pub tokens_per_minute: Option<u32>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            max_concurrency: 8,
+            requests_per_minute: None,
+            tokens_per_minute: None,
+        }
+    }
+}
+
+// Refills continuously at `capacity` per minute, starting full so a batch can burst.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    per_second: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn per_minute(capacity: u32) -> TokenBucket {
+        let capacity = f64::from(capacity.max(1));
+        TokenBucket {
+            capacity,
+            available: capacity,
+            per_second: capacity / 60.0,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.available = (self.available + elapsed * self.per_second).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    // Takes `amount` if it is there, otherwise says how long until it will be. Amounts larger
+    // than the whole bucket go through once it is full, or they would wait forever.
+    pub(crate) fn take(&mut self, amount: f64, now: Instant) -> Option<Duration> {
+        self.refill(now);
+        let needed = amount.min(self.capacity);
+        if self.available >= needed {
+            self.available -= amount;
+            None
+        } else {
+            Some(Duration::from_secs_f64((needed - self.available) / self.per_second))
+        }
+    }
+
+    // Charges for usage beyond what was taken up front; the balance may go negative.
+    pub(crate) fn charge(&mut self, amount: f64) {
+        self.available -= amount;
+    }
+}
+
+pub struct RateLimiter {
+    requests: Option<Mutex<TokenBucket>>,
+    tokens: Option<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: &RateLimits) -> RateLimiter {
+        RateLimiter {
+            requests: limits.requests_per_minute.map(|rpm| Mutex::new(TokenBucket::per_minute(rpm))),
+            tokens: limits.tokens_per_minute.map(|tpm| Mutex::new(TokenBucket::per_minute(tpm))),
+        }
+    }
+
+    // Waits until one request using about `tokens` tokens fits within both limits.
+    pub async fn acquire(&self, tokens: u32) {
+        Self::wait_for(&self.requests, 1.0).await;
+        Self::wait_for(&self.tokens, f64::from(tokens)).await;
+    }
+
+    // Corrects the token budget once the actual usage is known.
+    pub fn record_usage(&self, estimated: u32, actual: u32) {
+        if let Some(bucket) = &self.tokens {
+            if actual > estimated {
+                bucket.lock().unwrap().charge(f64::from(actual - estimated));
+            }
+        }
+    }
+
+    async fn wait_for(bucket: &Option<Mutex<TokenBucket>>, amount: f64) {
+        let Some(bucket) = bucket else {
+            return;
+        };
+        loop {
+            // the lock is released before sleeping
+            let wait = bucket.lock().unwrap().take(amount, Instant::now());
+            match wait {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None => return,
+            }
+        }
+    }
+}
+
+// What a payload is expected to cost against the tokens-per-minute limit.
+pub fn estimate_payload_tokens(payload: &Payload) -> u32 {
+    let prompt: usize = payload
+        .messages
+        .iter()
+        .map(|message| estimate_message_tokens(&payload.model, message))
+        .sum();
+    let completion = payload.max_tokens.unwrap_or(0).max(0) as usize;
+    u32::try_from(prompt + completion).unwrap_or(u32::MAX)
+}
+
+// Sends every payload within the limits. Results come back in input order, and a failed
+// item doesn't stop the others.
+pub async fn chat_many(
+    provider: &dyn ChatProvider,
+    payloads: Vec<Payload>,
+    limits: &RateLimits,
+) -> Vec<Result<ChatCompletion, OpenAiError>> {
+    let limiter = RateLimiter::new(limits);
+    let limiter = &limiter;
+
+    stream::iter(payloads)
+        .map(|payload| async move {
+            let estimated = estimate_payload_tokens(&payload);
+            limiter.acquire(estimated).await;
+
+            let completion = provider.complete(payload).await?;
+            if let Some(usage) = completion.usage {
+                limiter.record_usage(estimated, usage.total_tokens);
+            }
+            Ok(completion)
+        })
+        .buffered(limits.max_concurrency.max(1))
+        .collect()
+        .await
+}
+
+// chat_many for plain text prompts sent to the provider's default model.
+pub async fn prompt_many(
+    provider: &dyn ChatProvider,
+    prompts: Vec<String>,
+    limits: &RateLimits,
+) -> Vec<Result<Message, OpenAiError>> {
+    let payloads = prompts
+        .into_iter()
+        .map(|text| Payload {
+            model: provider.default_model(),
+            messages: vec![Message::user(text)],
+            ..Default::default()
+        })
+        .collect();
+
+    chat_many(provider, payloads, limits)
+        .await
+        .into_iter()
+        .map(|result| result.and_then(ChatCompletion::into_message))
+        .collect()
+}
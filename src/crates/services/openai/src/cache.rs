@@ -0,0 +1,210 @@
+use async_trait::async_trait;
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::chat::{ChatCompletion, ChatModel, ChatStream, Payload};
+use crate::error::OpenAiError;
+use crate::provider::ChatProvider;
+use crate::store::now;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+    // the normalized payload, kept so a hash collision on disk is noticed
+    pub key: String,
+    // seconds since the unix epoch
+    pub stored_at: u64,
+    pub completion: ChatCompletion,
+}
+
+pub trait CacheStore: Send + Sync {
+    fn get(&self, key: &str) -> Option<CacheEntry>;
+    fn put(&self, entry: CacheEntry);
+    fn remove(&self, key: &str);
+}
+
+// Keeps the most recently used completions in memory.
+pub struct MemoryCache {
+    entries: Mutex<LruCache<String, CacheEntry>>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> MemoryCache {
+        let capacity = NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN);
+        MemoryCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl CacheStore for MemoryCache {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn put(&self, entry: CacheEntry) {
+        self.entries.lock().unwrap().put(entry.key.clone(), entry);
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().pop(key);
+    }
+}
+
+// One JSON file per completion, so the cache survives between runs of a script.
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> DiskCache {
+        DiskCache { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", fnv1a(key.as_bytes())))
+    }
+}
+
+impl CacheStore for DiskCache {
+    fn get(&self, key: &str) -> Option<CacheEntry> {
+        let json = fs::read_to_string(self.path(key)).ok()?;
+        serde_json::from_str::<CacheEntry>(&json)
+            .ok()
+            .filter(|entry| entry.key == key)
+    }
+
+    // A cache is best effort: failing to write one is not worth failing the request over.
+    fn put(&self, entry: CacheEntry) {
+        let Ok(json) = serde_json::to_string(&entry) else {
+            return;
+        };
+        let path = self.path(&entry.key);
+        let tmp = path.with_extension("json.tmp");
+        let _ = fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&tmp, json))
+            .and_then(|_| fs::rename(&tmp, &path));
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = fs::remove_file(self.path(key));
+    }
+}
+
+// Stable across builds, unlike std's hasher, which matters for file names.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Only payloads that should give the same answer every time are cached: temperature 0 and a
+// fixed seed, and a single choice. Either alone still leaves room for a different answer.
+pub fn is_cacheable(payload: &Payload) -> bool {
+    let deterministic = payload.temperature == Some(0.0) && payload.seed.is_some();
+    deterministic && payload.n.unwrap_or(1) == 1
+}
+
+// The payload as canonical JSON (object keys sorted), without fields that don't change the answer.
+pub fn cache_key(provider: &str, payload: &Payload) -> String {
+    let mut value = json!(payload);
+    if let Value::Object(fields) = &mut value {
+        fields.remove("stream");
+        fields.remove("user");
+        fields.retain(|_, field| !field.is_null());
+    }
+    format!("{}:{}", provider, value)
+}
+
+// Wraps a provider and answers repeated deterministic payloads from a cache.
+pub struct CachedProvider<P> {
+    inner: P,
+    store: Box<dyn CacheStore>,
+    ttl: Option<Duration>,
+    bypass: AtomicBool,
+}
+
+impl<P: ChatProvider> CachedProvider<P> {
+    pub fn new<S: CacheStore + 'static>(inner: P, store: S) -> CachedProvider<P> {
+        CachedProvider {
+            inner,
+            store: Box::new(store),
+            ttl: None,
+            bypass: AtomicBool::new(false),
+        }
+    }
+
+    // Entries older than this are refetched.
+    pub fn with_ttl(mut self, ttl: Duration) -> CachedProvider<P> {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    // While bypassed every request goes to the provider; fresh answers still refresh the cache.
+    pub fn set_bypass(&self, bypass: bool) {
+        self.bypass.store(bypass, Ordering::Relaxed);
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    fn lookup(&self, key: &str) -> Option<ChatCompletion> {
+        if self.bypass.load(Ordering::Relaxed) {
+            return None;
+        }
+        let entry = self.store.get(key)?;
+        if let Some(ttl) = self.ttl {
+            if now().saturating_sub(entry.stored_at) >= ttl.as_secs() {
+                self.store.remove(key);
+                return None;
+            }
+        }
+        Some(entry.completion)
+    }
+}
+
+#[async_trait]
+impl<P: ChatProvider> ChatProvider for CachedProvider<P> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn default_model(&self) -> ChatModel {
+        self.inner.default_model()
+    }
+
+    async fn complete(&self, payload: Payload) -> Result<ChatCompletion, OpenAiError> {
+        if !is_cacheable(&payload) {
+            return self.inner.complete(payload).await;
+        }
+
+        let key = cache_key(self.inner.name(), &payload);
+        if let Some(completion) = self.lookup(&key) {
+            return Ok(completion);
+        }
+
+        let completion = self.inner.complete(payload).await?;
+        self.store.put(CacheEntry {
+            key,
+            stored_at: now(),
+            completion: completion.clone(),
+        });
+        Ok(completion)
+    }
+
+    // Streams are passed through; there is nothing to replay them from.
+    async fn stream(&self, payload: Payload) -> Result<ChatStream, OpenAiError> {
+        self.inner.stream(payload).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, OpenAiError> {
+        self.inner.list_models().await
+    }
+}
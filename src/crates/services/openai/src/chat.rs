@@ -1,151 +1,800 @@
-use reqwest::Client;
-use serde::{Deserialize, Serialize, Serializer};
-use serde_json::{from_value, json};
-use std::env;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use reqwest::Method;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::client::{default_client, OpenAiClient};
+use crate::completions::{CompletionRequest, Endpoint};
+pub use crate::content::{Content, ContentPart, ImageDetail, ImageUrl};
+use crate::error::OpenAiError;
+use crate::logprobs::ChoiceLogprobs;
+use crate::structured::ResponseFormat;
+use crate::tools::{FunctionCall, Tool, ToolCall, ToolChoice};
+use crate::usage;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    #[default]
+    User,
+    Assistant,
+    Tool,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct Message {
-    pub role: String,
-    pub content: String,
+    pub role: Role,
+    // assistant messages that only call tools come back with a null content
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: Content,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub fn new<C: Into<Content>>(role: Role, content: C) -> Message {
+        Message {
+            role,
+            content: content.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn system<C: Into<Content>>(content: C) -> Message {
+        Message::new(Role::System, content)
+    }
+
+    pub fn user<C: Into<Content>>(content: C) -> Message {
+        Message::new(Role::User, content)
+    }
+
+    pub fn assistant<C: Into<Content>>(content: C) -> Message {
+        Message::new(Role::Assistant, content)
+    }
+
+    // The reply to a tool call, matched to it by id.
+    pub fn tool<C: Into<Content>>(tool_call_id: &str, content: C) -> Message {
+        Message {
+            tool_call_id: Some(tool_call_id.to_string()),
+            ..Message::new(Role::Tool, content)
+        }
+    }
+}
+
+fn null_as_empty<'de, D>(deserializer: D) -> Result<Content, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<Content>::deserialize(deserializer)?.unwrap_or_default())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// Serialized as the model id the API uses; ids without a variant, like dated snapshots
+// or local models, round-trip through Custom.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
 pub enum ChatModel {
-    GPT3,
+    #[default]
     Gpt3Turbo,
     GPT4,
     Gpt4Turbo,
+    Gpt4o,
+    Gpt4oMini,
+    // completion models, only served by /v1/completions
     Gpt3TurboInstruct,
     Babbage002,
     Davinci002,
+    Custom(String),
 }
 
 impl ChatModel {
-    fn name(&self) -> String {
+    pub fn name(&self) -> &str {
         match self {
-            ChatModel::GPT3 => "gpt-3",
             ChatModel::Gpt3Turbo => "gpt-3.5-turbo",
             ChatModel::GPT4 => "gpt-4",
             ChatModel::Gpt4Turbo => "gpt-4-turbo",
+            ChatModel::Gpt4o => "gpt-4o",
+            ChatModel::Gpt4oMini => "gpt-4o-mini",
             ChatModel::Gpt3TurboInstruct => "gpt-3.5-turbo-instruct",
             ChatModel::Babbage002 => "babbage-002",
             ChatModel::Davinci002 => "davinci-002",
+            ChatModel::Custom(name) => name,
+        }
+    }
+}
+
+impl FromStr for ChatModel {
+    type Err = Infallible;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Ok(match name {
+            "gpt-3.5-turbo" => ChatModel::Gpt3Turbo,
+            "gpt-4" => ChatModel::GPT4,
+            "gpt-4-turbo" => ChatModel::Gpt4Turbo,
+            "gpt-4o" => ChatModel::Gpt4o,
+            "gpt-4o-mini" => ChatModel::Gpt4oMini,
+            "gpt-3.5-turbo-instruct" => ChatModel::Gpt3TurboInstruct,
+            "babbage-002" => ChatModel::Babbage002,
+            "davinci-002" => ChatModel::Davinci002,
+            other => ChatModel::Custom(other.to_string()),
+        })
+    }
+}
+
+impl From<&str> for ChatModel {
+    fn from(name: &str) -> Self {
+        match name.parse() {
+            Ok(model) => model,
+            Err(never) => match never {},
+        }
+    }
+}
+
+impl fmt::Display for ChatModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Serialize for ChatModel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for ChatModel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(ChatModel::from(String::deserialize(deserializer)?.as_str()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Payload {
+    pub(crate) model: ChatModel,
+    pub(crate) messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    // token id to a bias between -100 (never) and 100 (always)
+    pub(crate) logit_bias: Option<HashMap<u32, i8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) top_logprobs: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) n: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tools: Option<Vec<Tool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) tool_choice: Option<ToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) user: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct StreamOptions {
+    pub include_usage: bool,
+}
+
+impl Payload {
+    pub fn builder() -> PayloadBuilder {
+        PayloadBuilder::default()
+    }
+
+    pub fn model(&self) -> &ChatModel {
+        &self.model
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+}
+
+// Sets any option on a Payload; build checks the values against the API's documented ranges.
+#[derive(Debug, Default)]
+pub struct PayloadBuilder {
+    model: Option<ChatModel>,
+    payload: Payload,
+}
+
+impl PayloadBuilder {
+    pub fn model<M: Into<ChatModel>>(mut self, model: M) -> PayloadBuilder {
+        self.model = Some(model.into());
+        self
+    }
+
+    // Used when no model was set, e.g. a provider's own default.
+    pub fn default_model(mut self, model: ChatModel) -> PayloadBuilder {
+        self.model.get_or_insert(model);
+        self
+    }
+
+    pub fn messages(mut self, messages: Vec<Message>) -> PayloadBuilder {
+        self.payload.messages = messages;
+        self
+    }
+
+    pub fn message(mut self, message: Message) -> PayloadBuilder {
+        self.payload.messages.push(message);
+        self
+    }
+
+    pub fn system<C: Into<Content>>(self, content: C) -> PayloadBuilder {
+        self.message(Message::system(content))
+    }
+
+    pub fn user<C: Into<Content>>(self, content: C) -> PayloadBuilder {
+        self.message(Message::user(content))
+    }
+
+    pub fn frequency_penalty(mut self, penalty: f32) -> PayloadBuilder {
+        self.payload.frequency_penalty = Some(penalty);
+        self
+    }
+
+    pub fn presence_penalty(mut self, penalty: f32) -> PayloadBuilder {
+        self.payload.presence_penalty = Some(penalty);
+        self
+    }
+
+    pub fn logit_bias(mut self, bias: HashMap<u32, i8>) -> PayloadBuilder {
+        self.payload.logit_bias = Some(bias);
+        self
+    }
+
+    // Biases a single token; can be called repeatedly.
+    pub fn bias(mut self, token: u32, bias: i8) -> PayloadBuilder {
+        self.payload.logit_bias.get_or_insert_with(HashMap::new).insert(token, bias);
+        self
+    }
+
+    pub fn logprobs(mut self, logprobs: bool) -> PayloadBuilder {
+        self.payload.logprobs = Some(logprobs);
+        self
+    }
+
+    pub fn top_logprobs(mut self, top_logprobs: i32) -> PayloadBuilder {
+        self.payload.top_logprobs = Some(top_logprobs);
+        self
+    }
+
+    pub fn max_tokens(mut self, max_tokens: i32) -> PayloadBuilder {
+        self.payload.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn n(mut self, n: i32) -> PayloadBuilder {
+        self.payload.n = Some(n);
+        self
+    }
+
+    pub fn response_format(mut self, format: ResponseFormat) -> PayloadBuilder {
+        self.payload.response_format = Some(format);
+        self
+    }
+
+    pub fn seed(mut self, seed: i32) -> PayloadBuilder {
+        self.payload.seed = Some(seed);
+        self
+    }
+
+    pub fn stop(mut self, stop: Vec<String>) -> PayloadBuilder {
+        self.payload.stop = Some(stop);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> PayloadBuilder {
+        self.payload.temperature = Some(temperature);
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> PayloadBuilder {
+        self.payload.top_p = Some(top_p);
+        self
+    }
+
+    pub fn tools(mut self, tools: Vec<Tool>) -> PayloadBuilder {
+        self.payload.tools = Some(tools);
+        self
+    }
+
+    pub fn tool_choice(mut self, tool_choice: ToolChoice) -> PayloadBuilder {
+        self.payload.tool_choice = Some(tool_choice);
+        self
+    }
+
+    pub fn user_id(mut self, user: &str) -> PayloadBuilder {
+        self.payload.user = Some(user.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<Payload, OpenAiError> {
+        let mut payload = self.payload;
+        payload.model = self.model.unwrap_or_default();
+
+        if payload.messages.is_empty() {
+            return Err(invalid("messages must not be empty"));
+        }
+        check_range("temperature", payload.temperature, 0.0, 2.0)?;
+        check_range("top_p", payload.top_p, 0.0, 1.0)?;
+        check_range("frequency_penalty", payload.frequency_penalty, -2.0, 2.0)?;
+        check_range("presence_penalty", payload.presence_penalty, -2.0, 2.0)?;
+        check_range("top_logprobs", payload.top_logprobs, 0, 20)?;
+        for bias in payload.logit_bias.iter().flat_map(|bias| bias.values()) {
+            check_range("logit_bias", Some(*bias), -100, 100)?;
+        }
+        if payload.top_logprobs.is_some() && payload.logprobs != Some(true) {
+            return Err(invalid("top_logprobs requires logprobs"));
+        }
+        check_range("n", payload.n, 1, 128)?;
+        check_range("max_tokens", payload.max_tokens, 1, i32::MAX)?;
+        if payload.stop.as_ref().is_some_and(|stop| stop.len() > 4) {
+            return Err(invalid("stop takes at most 4 sequences"));
+        }
+        Ok(payload)
+    }
+}
+
+fn invalid(msg: &str) -> OpenAiError {
+    OpenAiError::InvalidRequest(msg.to_string())
+}
+
+pub(crate) fn check_range<T: PartialOrd + fmt::Display + Copy>(name: &str, value: Option<T>, min: T, max: T) -> Result<(), OpenAiError> {
+    match value {
+        // written so NaN fails too
+        Some(value) if !(value >= min && value <= max) => Err(OpenAiError::InvalidRequest(format!(
+            "{} must be between {} and {}, got {}",
+            name, min, max, value
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatCompletion {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub object: String,
+    #[serde(default)]
+    pub created: u64,
+    pub model: ChatModel,
+    pub choices: Vec<Choice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Choice {
+    pub index: u32,
+    pub message: Message,
+    pub finish_reason: Option<FinishReason>,
+    #[serde(default)]
+    pub logprobs: Option<ChoiceLogprobs>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    Stop,
+    // hit max_tokens or the context window; the message is truncated
+    Length,
+    ToolCalls,
+    ContentFilter,
+    FunctionCall,
+    #[serde(other)]
+    Other,
+}
+
+impl ChatCompletion {
+    pub fn first_message(&self) -> Option<&Message> {
+        self.choices.first().map(|choice| &choice.message)
+    }
+
+    pub fn into_message(self) -> Result<Message, OpenAiError> {
+        self.choices
+            .into_iter()
+            .next()
+            .map(|choice| choice.message)
+            .ok_or_else(|| OpenAiError::MalformedResponse("Response contained no choices".to_string()))
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.choices.iter().any(|choice| choice.finish_reason == Some(FinishReason::Length))
+    }
+
+    // Reports the token counts to the global usage tracker.
+    pub(crate) fn track_usage(&self) {
+        if let Some(usage) = self.usage {
+            usage::tracker().record(self.model.name(), usage.prompt_tokens, usage.completion_tokens);
         }
-        .to_string()
     }
 }
 
-fn serialize_openai_chat_model<S>(model: &ChatModel, serializer: S) -> Result<S::Ok, S::Error>
+impl OpenAiClient {
+    // Models only served by /v1/completions are sent there and answered in chat shape.
+    pub async fn chat(&self, payload: Payload) -> Result<ChatCompletion, OpenAiError> {
+        if payload.model.endpoint() == Endpoint::Completions {
+            let request = CompletionRequest::from_payload(&payload)?;
+            return Ok(self.completions(&request).await?.into_chat());
+        }
+
+        let request = self.request(Method::POST, "chat/completions").json(&json!(payload));
+        let body = self.send(request).await?.text().await?;
+
+        let completion = serde_json::from_str::<ChatCompletion>(&body)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing response body: {}", e)))?;
+        completion.track_usage();
+        Ok(completion)
+    }
+
+    pub async fn chat_stream(&self, mut payload: Payload) -> Result<ChatStream, OpenAiError> {
+        if payload.model.endpoint() == Endpoint::Completions {
+            return self.completions_stream(CompletionRequest::from_payload(&payload)?).await;
+        }
+        payload.stream = Some(true);
+        // ask for a final chunk with the token counts so streamed calls are tracked too
+        if self.config().stream_usage {
+            payload.stream_options = Some(StreamOptions { include_usage: true });
+        }
+        let request = self.request(Method::POST, "chat/completions").json(&json!(payload));
+        let response = self.send(request).await?;
+
+        Ok(ChatStream::new(response))
+    }
+
+    pub async fn prompt(&self, text: String, mut conversation: Vec<Message>) -> Result<Message, OpenAiError> {
+        conversation.push(Message::user(text));
+
+        let payload = Payload {
+            messages: conversation,
+            ..Default::default()
+        };
+
+        self.chat(payload).await?.into_message()
+    }
+
+    pub async fn prompt_stream(&self, text: String, mut conversation: Vec<Message>) -> Result<ChatStream, OpenAiError> {
+        conversation.push(Message::user(text));
+
+        let payload = Payload {
+            messages: conversation,
+            ..Default::default()
+        };
+
+        self.chat_stream(payload).await
+    }
+}
+
+pub async fn chat(payload: Payload) -> Result<ChatCompletion, OpenAiError> {
+    default_client()?.chat(payload).await
+}
+
+pub async fn chat_stream(payload: Payload) -> Result<ChatStream, OpenAiError> {
+    default_client()?.chat_stream(payload).await
+}
+
+pub async fn prompt(text: String, conversation: Vec<Message>) -> Result<Message, OpenAiError> {
+    default_client()?.prompt(text, conversation).await
+}
+
+// The sync helpers build their own client: pooled connections can't outlive the runtime they were made on.
+pub fn prompt_sync(text: String, conversation: Vec<Message>) -> Result<Message, OpenAiError> {
+    let client = OpenAiClient::from_env()?;
+    let rt = tokio::runtime::Runtime::new().map_err(OpenAiError::Runtime)?;
+
+    rt.block_on(client.prompt(text, conversation))
+}
+
+pub async fn prompt_stream(text: String, conversation: Vec<Message>) -> Result<ChatStream, OpenAiError> {
+    default_client()?.prompt_stream(text, conversation).await
+}
+
+// Blocking variant of prompt_stream for callers without a runtime; each token is
+// handed to on_token as it arrives and the assembled message is returned at the end.
+pub fn prompt_stream_sync<F>(text: String, conversation: Vec<Message>, mut on_token: F) -> Result<Message, OpenAiError>
 where
-    S: Serializer,
+    F: FnMut(&str),
 {
-    serializer.serialize_str(&model.name())
+    let client = OpenAiClient::from_env()?;
+    let rt = tokio::runtime::Runtime::new().map_err(OpenAiError::Runtime)?;
+
+    rt.block_on(async {
+        let mut stream = client.prompt_stream(text, conversation).await?;
+        while let Some(token) = stream.next().await {
+            on_token(&token?);
+        }
+        Ok(stream.message())
+    })
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Payload {
-    #[serde(serialize_with = "serialize_openai_chat_model")]
-    model: ChatModel,
-    messages: Vec<Message>,
-    frequency_penalty: Option<f32>,
-    logit_bias: Option<f32>,
-    logprobs: Option<i32>,
-    top_logprobs: Option<i32>,
-    max_tokens: Option<i32>,
-    n: Option<i32>,
-    presence_penalty: Option<f32>,
-    response_format: Option<String>,
-    seed: Option<i32>,
-    stop: Option<Vec<String>>,
-    stream: Option<bool>,
-    temperature: Option<f32>,
-    top_p: Option<f32>,
-    tools: Option<Vec<String>>,
-    tool_choice: Option<i32>,
-    user: Option<String>,
-}
-
-impl Default for Payload {
-    fn default() -> Self {
-        Payload {
-            model: ChatModel::Gpt3Turbo,
-            messages: vec![],
-            frequency_penalty: None,
-            logit_bias: None,
-            logprobs: None,
-            top_logprobs: None,
-            max_tokens: None,
-            n: None,
-            presence_penalty: None,
-            response_format: None,
-            seed: None,
-            stop: None,
-            stream: None,
-            temperature: None,
-            top_p: None,
-            tools: None,
-            tool_choice: None,
-            user: None,
-        }
-    }
-}
-
-pub async fn chat(payload: Payload) -> Result<String, String> {
-    let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
-    let client = Client::new();
-
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&json!(payload))
-        .send()
-        .await
-        .map_err(|e| format!("Failed to send request: {}", e))?;
-
-    if response.status().is_success() {
-        response
-            .text()
-            .await
-            .map_err(|e| format!("Failed to read response: {}", e))
-    } else {
-        Err(format!(
-            "API Request Failed: {}",
-            response
-                .text()
-                .await
-                .unwrap_or_else(|_| "Unknown error".to_string())
-        ))
-    }
-}
-
-pub async fn prompt(text: String, mut conversation: Vec<Message>) -> Result<Message, String> {
-    conversation.push(Message {
-        role: "user".to_string(),
-        content: text,
-    });
-
-    let payload = Payload {
-        messages: conversation,
-        ..Default::default()
-    };
-
-    let response = chat(payload).await;
-    match response {
-        Ok(body) => {
-            let body_value = serde_json::from_str::<serde_json::Value>(&body)
-                .map_err(|_| "Error parsing response body")?;
-
-            let first_choice = body_value["choices"][0].clone();
-            let message_value = first_choice["message"].clone();
-            from_value::<Message>(message_value).map_err(|_| "Error parsing response message".to_string())
-        }
-        Err(e) => Err(format!("Chat API error: {}", e)),
-    }
-}
-
-pub fn prompt_sync(text: String, conversation: Vec<Message>) -> Result<Message, String> {
-    let rt = tokio::runtime::Runtime::new().map_err(|e| format!("Failed to create runtime: {}", e))?;
-
-    rt.block_on(prompt(text, conversation))
-}
\ No newline at end of file
+#[derive(Deserialize, Debug, Default)]
+pub struct ChatChunk {
+    pub id: Option<String>,
+    #[serde(default)]
+    pub model: Option<ChatModel>,
+    pub choices: Vec<ChunkChoice>,
+    // only on the last chunk, and only when stream_options asked for it
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ChunkChoice {
+    pub index: u32,
+    pub delta: Delta,
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Delta {
+    pub role: Option<Role>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+// A fragment of a tool call; the id and name arrive first, the arguments in pieces.
+#[derive(Deserialize, Debug, Default)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub function: Option<FunctionCallDelta>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+// Splits a server-sent-event byte stream into the `data:` payload of each event.
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseDecoder {
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+
+        let mut events = vec![];
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                self.data.push(data.strip_prefix(' ').unwrap_or(data).to_string());
+            }
+            // comments (":") and other fields (event, id, retry) are not used by the API
+        }
+        events
+    }
+
+    // Flushes an event left unterminated when the connection closed.
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.buffer);
+        let events = self.push(&[&rest[..], b"\n\n"].concat());
+        events.into_iter().next()
+    }
+}
+
+// Turns the data of one server-sent event into a chunk; None for events that carry nothing.
+pub(crate) type ChunkParser = Box<dyn FnMut(&str) -> Result<Option<ChatChunk>, OpenAiError> + Send>;
+
+pub struct ChatStream {
+    body: Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send>>,
+    decoder: SseDecoder,
+    parse: ChunkParser,
+    pending: VecDeque<String>,
+    role: Option<Role>,
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    finish_reason: Option<FinishReason>,
+    model: Option<ChatModel>,
+    usage: Option<Usage>,
+    done: bool,
+}
+
+impl ChatStream {
+    pub fn new(response: reqwest::Response) -> Self {
+        let parse = |data: &str| {
+            serde_json::from_str::<ChatChunk>(data)
+                .map(Some)
+                .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing stream chunk: {}", e)))
+        };
+        ChatStream::with_parser(response, Box::new(parse))
+    }
+
+    // For backends whose events aren't OpenAI chunks; the parser translates each one.
+    pub(crate) fn with_parser(response: reqwest::Response, parse: ChunkParser) -> Self {
+        ChatStream {
+            body: Box::pin(response.bytes_stream()),
+            decoder: SseDecoder::default(),
+            parse,
+            pending: VecDeque::new(),
+            role: None,
+            content: String::new(),
+            tool_calls: vec![],
+            finish_reason: None,
+            model: None,
+            usage: None,
+            done: false,
+        }
+    }
+
+    // The message assembled from every delta received so far.
+    pub fn message(&self) -> Message {
+        Message {
+            role: self.role.unwrap_or(Role::Assistant),
+            content: Content::Text(self.content.clone()),
+            tool_calls: (!self.tool_calls.is_empty()).then(|| self.tool_calls.clone()),
+            tool_call_id: None,
+        }
+    }
+
+    // Token counts, if the backend sent them at the end of the stream.
+    pub fn usage(&self) -> Option<Usage> {
+        self.usage
+    }
+
+    // Set once the final chunk has arrived.
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    pub async fn collect_message(mut self) -> Result<Message, OpenAiError> {
+        while let Some(token) = self.next().await {
+            token?;
+        }
+        Ok(self.message())
+    }
+
+    fn handle_event(&mut self, data: &str) -> Result<(), OpenAiError> {
+        let Some(chunk) = (self.parse)(data)? else {
+            return Ok(());
+        };
+
+        if chunk.model.is_some() {
+            self.model = chunk.model;
+        }
+        if let Some(usage) = chunk.usage {
+            self.usage = Some(usage);
+            if let Some(model) = &self.model {
+                usage::tracker().record(model.name(), usage.prompt_tokens, usage.completion_tokens);
+            }
+        }
+
+        for choice in chunk.choices.into_iter().filter(|c| c.index == 0) {
+            if let Some(role) = choice.delta.role {
+                self.role = Some(role);
+            }
+            if let Some(content) = choice.delta.content.filter(|c| !c.is_empty()) {
+                self.content.push_str(&content);
+                self.pending.push_back(content);
+            }
+            for delta in choice.delta.tool_calls.unwrap_or_default() {
+                self.merge_tool_call(delta);
+            }
+            if choice.finish_reason.is_some() {
+                self.finish_reason = choice.finish_reason;
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_tool_call(&mut self, delta: ToolCallDelta) {
+        while self.tool_calls.len() <= delta.index {
+            self.tool_calls.push(ToolCall {
+                id: String::new(),
+                kind: "function".to_string(),
+                function: FunctionCall::default(),
+            });
+        }
+
+        let call = &mut self.tool_calls[delta.index];
+        if let Some(id) = delta.id {
+            call.id = id;
+        }
+        if let Some(function) = delta.function {
+            if let Some(name) = function.name {
+                call.function.name.push_str(&name);
+            }
+            if let Some(arguments) = function.arguments {
+                call.function.arguments.push_str(&arguments);
+            }
+        }
+    }
+}
+
+impl Stream for ChatStream {
+    type Item = Result<String, OpenAiError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(token) = self.pending.pop_front() {
+                return Poll::Ready(Some(Ok(token)));
+            }
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            let events = match self.body.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(bytes))) => self.decoder.push(&bytes),
+                Poll::Ready(Some(Err(e))) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(OpenAiError::Transport(e))));
+                }
+                Poll::Ready(None) => {
+                    self.done = true;
+                    self.decoder.finish().into_iter().collect()
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            for data in events {
+                if data == "[DONE]" {
+                    self.done = true;
+                    break;
+                }
+                if let Err(e) = self.handle_event(&data) {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
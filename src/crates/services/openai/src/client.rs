@@ -0,0 +1,231 @@
+use reqwest::multipart::Form;
+use reqwest::{Method, RequestBuilder, Response};
+use std::env;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::error::OpenAiError;
+use crate::retry::{self, RetryPolicy};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+// How the API key is presented to the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthScheme {
+    // `Authorization: Bearer <key>`, used by OpenAI and most compatible servers
+    Bearer,
+    // the key in a custom header, e.g. `api-key` for Azure OpenAI
+    Header(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub base_url: String,
+    pub api_key: Option<String>,
+    pub auth: AuthScheme,
+    pub organization: Option<String>,
+    pub project: Option<String>,
+    // query parameters added to every request, e.g. Azure's `api-version`
+    pub query: Vec<(String, String)>,
+    // extra headers sent with every request, e.g. Anthropic's `anthropic-version`
+    pub headers: Vec<(String, String)>,
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    pub retry: RetryPolicy,
+    // asks streams for a final usage chunk via `stream_options`, which some compatible servers
+    // and older Azure api-versions reject; on by default only for api.openai.com
+    pub stream_usage: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            base_url: DEFAULT_BASE_URL.to_string(),
+            api_key: None,
+            auth: AuthScheme::Bearer,
+            organization: None,
+            project: None,
+            query: vec![],
+            headers: vec![],
+            timeout: Some(Duration::from_secs(600)),
+            connect_timeout: Some(Duration::from_secs(10)),
+            retry: RetryPolicy::default(),
+            stream_usage: true,
+        }
+    }
+}
+
+impl ClientConfig {
+    // Reads OPENAI_API_KEY, OPENAI_BASE_URL, OPENAI_ORG_ID and OPENAI_PROJECT_ID.
+    pub fn from_env() -> ClientConfig {
+        let base_url = env::var("OPENAI_BASE_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_string());
+        ClientConfig {
+            stream_usage: is_openai(&base_url),
+            base_url,
+            api_key: env::var("OPENAI_API_KEY").ok(),
+            organization: env::var("OPENAI_ORG_ID").ok(),
+            project: env::var("OPENAI_PROJECT_ID").ok(),
+            ..Default::default()
+        }
+    }
+
+    // An Azure OpenAI deployment, e.g. `https://my-resource.openai.azure.com`.
+    pub fn azure(resource_url: &str, deployment: &str, api_version: &str, api_key: &str) -> ClientConfig {
+        ClientConfig {
+            base_url: format!("{}/openai/deployments/{}", resource_url.trim_end_matches('/'), deployment),
+            api_key: Some(api_key.to_string()),
+            auth: AuthScheme::Header("api-key".to_string()),
+            query: vec![("api-version".to_string(), api_version.to_string())],
+            stream_usage: false,
+            ..Default::default()
+        }
+    }
+
+    // An OpenAI-compatible server that needs no key, e.g. Ollama at `http://localhost:11434/v1`.
+    pub fn local(base_url: &str) -> ClientConfig {
+        ClientConfig {
+            base_url: base_url.to_string(),
+            stream_usage: is_openai(base_url),
+            ..Default::default()
+        }
+    }
+}
+
+fn is_openai(base_url: &str) -> bool {
+    base_url.starts_with("https://api.openai.com/")
+}
+
+// Holds the configuration and a pooled HTTP client; cheap to clone and share.
+#[derive(Debug, Clone)]
+pub struct OpenAiClient {
+    pub(crate) http: reqwest::Client,
+    config: ClientConfig,
+}
+
+impl OpenAiClient {
+    pub fn new(config: ClientConfig) -> Result<OpenAiClient, OpenAiError> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = config.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        Ok(OpenAiClient {
+            http: builder.build()?,
+            config,
+        })
+    }
+
+    // Only the official endpoint insists on a key; local servers usually run without one.
+    pub fn from_env() -> Result<OpenAiClient, OpenAiError> {
+        let config = ClientConfig::from_env();
+        if config.api_key.is_none() && config.base_url == DEFAULT_BASE_URL {
+            return Err(OpenAiError::MissingApiKey);
+        }
+        OpenAiClient::new(config)
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.config.base_url.trim_end_matches('/'),
+            path.trim_start_matches('/')
+        )
+    }
+
+    pub(crate) fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut request = self.http.request(method, self.url(path));
+
+        if let Some(api_key) = &self.config.api_key {
+            request = match &self.config.auth {
+                AuthScheme::Bearer => request.bearer_auth(api_key),
+                AuthScheme::Header(name) => request.header(name.as_str(), api_key.as_str()),
+            };
+        }
+        if let Some(organization) = &self.config.organization {
+            request = request.header("OpenAI-Organization", organization.as_str());
+        }
+        if let Some(project) = &self.config.project {
+            request = request.header("OpenAI-Project", project.as_str());
+        }
+        for (name, value) in &self.config.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        if !self.config.query.is_empty() {
+            request = request.query(&self.config.query);
+        }
+        request
+    }
+
+    // Sends a request that is safe to replay, retrying per the configured policy and
+    // turning non-success statuses into errors.
+    pub(crate) async fn send(&self, request: RequestBuilder) -> Result<Response, OpenAiError> {
+        self.send_with(request, true).await
+    }
+
+    // Uploads are sent exactly once: a multipart form is streamed, so it can't be cloned for a
+    // retry, even when resending would otherwise be safe.
+    pub(crate) async fn send_multipart(&self, path: &str, form: Form) -> Result<Response, OpenAiError> {
+        self.send(self.request(Method::POST, path).multipart(form)).await
+    }
+
+    // Like send, but requests that create state on the server pass idempotent = false
+    // so they are only resent when the server certainly didn't process them.
+    pub(crate) async fn send_with(&self, request: RequestBuilder, idempotent: bool) -> Result<Response, OpenAiError> {
+        let policy = &self.config.retry;
+        let mut attempt = 1;
+
+        loop {
+            // streaming bodies can't be cloned, so those get exactly one attempt
+            let Some(current) = request.try_clone() else {
+                return Self::send_once(request).await;
+            };
+
+            let (error, retryable, hint) = match current.send().await {
+                Ok(response) if response.status().is_success() => return Ok(response),
+                Ok(response) => {
+                    let retryable = policy.should_retry_status(response.status(), idempotent);
+                    let hint = retry::delay_from_headers(response.headers());
+                    (OpenAiError::from_response(response).await, retryable, hint)
+                }
+                Err(e) => {
+                    let retryable = policy.should_retry_error(&e, idempotent);
+                    (OpenAiError::Transport(e), retryable, None)
+                }
+            };
+
+            if !retryable || attempt >= policy.max_attempts {
+                return Err(error);
+            }
+            tokio::time::sleep(policy.delay(attempt, hint)).await;
+            attempt += 1;
+        }
+    }
+
+    async fn send_once(request: RequestBuilder) -> Result<Response, OpenAiError> {
+        let response = request.send().await?;
+
+        if response.status().is_success() {
+            Ok(response)
+        } else {
+            Err(OpenAiError::from_response(response).await)
+        }
+    }
+}
+
+static DEFAULT_CLIENT: OnceLock<OpenAiClient> = OnceLock::new();
+
+// The environment-configured client shared by the free functions in each module.
+pub fn default_client() -> Result<&'static OpenAiClient, OpenAiError> {
+    if let Some(client) = DEFAULT_CLIENT.get() {
+        return Ok(client);
+    }
+    let client = OpenAiClient::from_env()?;
+    Ok(DEFAULT_CLIENT.get_or_init(|| client))
+}
@@ -0,0 +1,367 @@
+use reqwest::{Method, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::env;
+use std::sync::OnceLock;
+
+use crate::chat::{
+    ChatChunk, ChatCompletion, ChatModel, ChatStream, Choice, ChunkChoice, Content, Delta, FinishReason, Message, Payload,
+    Role, Usage,
+};
+use crate::client::{default_client, OpenAiClient};
+use crate::error::OpenAiError;
+use crate::logprobs::{ChoiceLogprobs, TokenLogprob, TopLogprob};
+
+// The two generation endpoints; each model is served by exactly one of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Chat,
+    Completions,
+}
+
+impl Endpoint {
+    pub fn path(self) -> &'static str {
+        match self {
+            Endpoint::Chat => "/v1/chat/completions",
+            Endpoint::Completions => "/v1/completions",
+        }
+    }
+}
+
+impl ChatModel {
+    // Custom ids, e.g. local instruct-tuned chat models, go to the chat endpoint unless they are
+    // fine-tunes of a legacy completion model or listed in OPENAI_COMPLETION_MODELS.
+    pub fn endpoint(&self) -> Endpoint {
+        match self {
+            ChatModel::Gpt3TurboInstruct | ChatModel::Babbage002 | ChatModel::Davinci002 => Endpoint::Completions,
+            ChatModel::Custom(name)
+                if name.starts_with("ft:babbage-002:")
+                    || name.starts_with("ft:davinci-002:")
+                    || completion_models().iter().any(|model| model == name) =>
+            {
+                Endpoint::Completions
+            }
+            _ => Endpoint::Chat,
+        }
+    }
+}
+
+static COMPLETION_MODELS: OnceLock<Vec<String>> = OnceLock::new();
+
+// Extra model ids served by /v1/completions, comma separated in OPENAI_COMPLETION_MODELS.
+fn completion_models() -> &'static [String] {
+    COMPLETION_MODELS.get_or_init(|| {
+        env::var("OPENAI_COMPLETION_MODELS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|model| !model.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CompletionRequest {
+    pub model: ChatModel,
+    pub prompt: String,
+    // text that comes after the insertion, for fill-in-the-middle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<i32>,
+    // generate this many server-side and return the n best; must be at least n
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_of: Option<i32>,
+    // number of most likely tokens to return per position, at most 5
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<u8>,
+    // include the prompt in the returned text
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub echo: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<HashMap<u32, i8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl CompletionRequest {
+    pub fn new<S: Into<String>>(model: ChatModel, prompt: S) -> CompletionRequest {
+        CompletionRequest {
+            model,
+            prompt: prompt.into(),
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), OpenAiError> {
+        if self.model.endpoint() != Endpoint::Completions {
+            return Err(OpenAiError::WrongEndpoint {
+                model: self.model.to_string(),
+                endpoint: Endpoint::Completions.path(),
+            });
+        }
+        if self.logprobs.is_some_and(|logprobs| logprobs > 5) {
+            return Err(OpenAiError::InvalidRequest("logprobs must be at most 5".to_string()));
+        }
+        if let Some(best_of) = self.best_of {
+            if best_of < self.n.unwrap_or(1) {
+                return Err(OpenAiError::InvalidRequest("best_of must be at least n".to_string()));
+            }
+            if self.stream == Some(true) {
+                return Err(OpenAiError::InvalidRequest("best_of can't be combined with streaming".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    // Renders a chat payload as a plain prompt. Features only chat models have are refused
+    // rather than silently dropped.
+    pub fn from_payload(payload: &Payload) -> Result<CompletionRequest, OpenAiError> {
+        let unsupported = if payload.tools.is_some() {
+            Some("tools")
+        } else if payload.response_format.is_some() {
+            Some("response_format")
+        } else if payload.messages.iter().any(|message| matches!(message.content, Content::Parts(_))) {
+            Some("image input")
+        } else {
+            None
+        };
+        if let Some(feature) = unsupported {
+            return Err(OpenAiError::InvalidRequest(format!(
+                "{} is not supported by {}, which is only served by {}",
+                feature,
+                payload.model,
+                Endpoint::Completions.path()
+            )));
+        }
+
+        Ok(CompletionRequest {
+            model: payload.model.clone(),
+            prompt: render_prompt(&payload.messages),
+            max_tokens: payload.max_tokens,
+            temperature: payload.temperature,
+            top_p: payload.top_p,
+            n: payload.n,
+            logprobs: match payload.logprobs {
+                Some(true) => Some(payload.top_logprobs.unwrap_or(0).clamp(0, 5) as u8),
+                _ => None,
+            },
+            stop: payload.stop.clone(),
+            presence_penalty: payload.presence_penalty,
+            frequency_penalty: payload.frequency_penalty,
+            logit_bias: payload.logit_bias.clone(),
+            seed: payload.seed,
+            user: payload.user.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+// A lone question is sent as is; longer exchanges become a labelled transcript ending where
+// the assistant should pick up.
+fn render_prompt(messages: &[Message]) -> String {
+    let system: Vec<String> = messages
+        .iter()
+        .filter(|message| message.role == Role::System)
+        .map(|message| message.content.text())
+        .collect();
+    let turns: Vec<&Message> = messages.iter().filter(|message| message.role != Role::System).collect();
+
+    let mut sections = system;
+    match turns.as_slice() {
+        [only] if only.role == Role::User => sections.push(only.content.text()),
+        _ => {
+            for message in turns {
+                sections.push(format!("{}: {}", message.role, message.content.text()));
+            }
+            sections.push(format!("{}:", Role::Assistant));
+        }
+    }
+    sections.join("\n\n")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletionResponse {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub created: u64,
+    pub model: ChatModel,
+    pub choices: Vec<CompletionChoice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletionChoice {
+    pub index: u32,
+    pub text: String,
+    #[serde(default)]
+    pub logprobs: Option<CompletionLogprobs>,
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompletionLogprobs {
+    pub tokens: Vec<String>,
+    // None for the first token when echoing the prompt
+    pub token_logprobs: Vec<Option<f64>>,
+    #[serde(default)]
+    pub top_logprobs: Option<Vec<HashMap<String, f64>>>,
+    #[serde(default)]
+    pub text_offset: Vec<usize>,
+}
+
+impl CompletionLogprobs {
+    // The chat shape of the same numbers; tokens without a logprob, which only happen when
+    // echoing the prompt, are left out.
+    pub fn into_chat(self) -> ChoiceLogprobs {
+        let mut top = self.top_logprobs.unwrap_or_default().into_iter();
+        let content = self
+            .tokens
+            .into_iter()
+            .zip(self.token_logprobs)
+            .filter_map(|(token, logprob)| {
+                let alternatives = top.next().unwrap_or_default();
+                let logprob = logprob?;
+                let mut top_logprobs: Vec<TopLogprob> = alternatives
+                    .into_iter()
+                    .map(|(token, logprob)| TopLogprob {
+                        bytes: Some(token.as_bytes().to_vec()),
+                        token,
+                        logprob,
+                    })
+                    .collect();
+                top_logprobs.sort_by(|a, b| b.logprob.total_cmp(&a.logprob));
+                Some(TokenLogprob {
+                    bytes: Some(token.as_bytes().to_vec()),
+                    token,
+                    logprob,
+                    top_logprobs,
+                })
+            })
+            .collect();
+        ChoiceLogprobs {
+            content: Some(content),
+            refusal: None,
+        }
+    }
+}
+
+impl CompletionResponse {
+    // The same answer in chat shape, one assistant message per choice.
+    pub fn into_chat(self) -> ChatCompletion {
+        ChatCompletion {
+            id: self.id,
+            object: "chat.completion".to_string(),
+            created: self.created,
+            model: self.model,
+            choices: self
+                .choices
+                .into_iter()
+                .map(|choice| Choice {
+                    index: choice.index,
+                    message: Message::assistant(choice.text),
+                    finish_reason: choice.finish_reason,
+                    logprobs: choice.logprobs.map(CompletionLogprobs::into_chat),
+                })
+                .collect(),
+            usage: self.usage,
+            system_fingerprint: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CompletionChunk {
+    #[serde(default)]
+    model: Option<ChatModel>,
+    #[serde(default)]
+    choices: Vec<CompletionChunkChoice>,
+    #[serde(default)]
+    usage: Option<Usage>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChunkChoice {
+    index: u32,
+    #[serde(default)]
+    text: String,
+    finish_reason: Option<FinishReason>,
+}
+
+impl OpenAiClient {
+    pub async fn completions(&self, request: &CompletionRequest) -> Result<CompletionResponse, OpenAiError> {
+        request.validate()?;
+        let http_request = self.request(Method::POST, "completions").json(request);
+        let body = self.send(http_request).await?.text().await?;
+
+        let response = serde_json::from_str::<CompletionResponse>(&body)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing completion: {}", e)))?;
+        if let Some(usage) = response.usage {
+            crate::usage::tracker().record(response.model.name(), usage.prompt_tokens, usage.completion_tokens);
+        }
+        Ok(response)
+    }
+
+    // Streams a completion as a ChatStream, so callers handle both endpoints the same way.
+    pub async fn completions_stream(&self, mut request: CompletionRequest) -> Result<ChatStream, OpenAiError> {
+        request.stream = Some(true);
+        request.validate()?;
+        let mut body = json!(request);
+        if self.config().stream_usage {
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+        let http_request = self.request(Method::POST, "completions").json(&body);
+        let response = self.send(http_request).await?;
+
+        Ok(completion_stream(response))
+    }
+}
+
+fn completion_stream(response: Response) -> ChatStream {
+    let parse = |data: &str| {
+        let chunk = serde_json::from_str::<CompletionChunk>(data)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing stream chunk: {}", e)))?;
+        Ok(Some(ChatChunk {
+            id: None,
+            model: chunk.model,
+            choices: chunk
+                .choices
+                .into_iter()
+                .map(|choice| ChunkChoice {
+                    index: choice.index,
+                    delta: Delta {
+                        content: Some(choice.text),
+                        ..Default::default()
+                    },
+                    finish_reason: choice.finish_reason,
+                })
+                .collect(),
+            usage: chunk.usage,
+        }))
+    };
+    ChatStream::with_parser(response, Box::new(parse))
+}
+
+pub async fn completions(request: &CompletionRequest) -> Result<CompletionResponse, OpenAiError> {
+    default_client()?.completions(request).await
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+
+use crate::chat::{ChatModel, Content, ContentPart, Message, Payload, Role};
+use crate::error::OpenAiError;
+use crate::provider::ChatProvider;
+
+// Tokens the API adds around every message for the role and separators.
+const MESSAGE_OVERHEAD: usize = 4;
+// A high-detail image costs at most this many tokens.
+const IMAGE_TOKENS: usize = 765;
+
+// What to do with old turns once the conversation outgrows its budget.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrimStrategy {
+    #[default]
+    DropOldest,
+    // fold the dropped turns into a running summary written by the model
+    Summarize,
+}
+
+// A chat session that keeps its own history and stays inside the model's context window.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Conversation {
+    pub model: ChatModel,
+    pub system: Option<String>,
+    pub summary: Option<String>,
+    pub messages: Vec<Message>,
+    pub max_context_tokens: usize,
+    // room left for the reply when checking the budget
+    pub reserve_tokens: usize,
+    pub strategy: TrimStrategy,
+}
+
+impl Conversation {
+    pub fn new(model: ChatModel, system: Option<String>) -> Conversation {
+        Conversation {
+            max_context_tokens: context_window(&model),
+            model,
+            system,
+            summary: None,
+            messages: vec![],
+            reserve_tokens: 1000,
+            strategy: TrimStrategy::default(),
+        }
+    }
+
+    pub fn with_budget(mut self, max_context_tokens: usize, reserve_tokens: usize) -> Conversation {
+        self.max_context_tokens = max_context_tokens;
+        self.reserve_tokens = reserve_tokens;
+        self
+    }
+
+    pub fn with_strategy(mut self, strategy: TrimStrategy) -> Conversation {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn push(&mut self, message: Message) {
+        self.messages.push(message);
+    }
+
+    // The messages to send: system prompt, summary of trimmed turns, then the history.
+    pub fn to_messages(&self) -> Vec<Message> {
+        let mut messages = vec![];
+        if let Some(system) = &self.system {
+            messages.push(Message::system(system.as_str()));
+        }
+        if let Some(summary) = &self.summary {
+            messages.push(Message::system(format!("Summary of the earlier conversation: {}", summary)));
+        }
+        messages.extend(self.messages.iter().cloned());
+        messages
+    }
+
+    pub fn estimated_tokens(&self) -> usize {
+        self.to_messages()
+            .iter()
+            .map(|message| estimate_message_tokens(&self.model, message))
+            .sum()
+    }
+
+    pub fn is_over_budget(&self) -> bool {
+        self.estimated_tokens() + self.reserve_tokens > self.max_context_tokens
+    }
+
+    // Removes whole turns from the front until the conversation fits, always keeping the
+    // latest turn. Returns what was removed.
+    pub fn trim(&mut self) -> Vec<Message> {
+        let mut removed = vec![];
+        while self.is_over_budget() {
+            let end = self.oldest_turn_end();
+            if end == 0 {
+                break;
+            }
+            removed.extend(self.messages.drain(..end));
+        }
+        removed
+    }
+
+    // A turn starts at a user message, so tool calls stay with their replies.
+    fn oldest_turn_end(&self) -> usize {
+        let next_turn = self
+            .messages
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|(_, message)| message.role == Role::User)
+            .map(|(i, _)| i);
+
+        next_turn.unwrap_or_default()
+    }
+
+    // Appends the user's text, keeps the history within budget, asks the model and appends its reply.
+    // The work happens on a copy, so a failed summary or reply leaves the conversation as it was.
+    pub async fn send<C: Into<Content>>(&mut self, provider: &dyn ChatProvider, content: C) -> Result<Message, OpenAiError> {
+        let mut next = self.clone();
+        next.push(Message::user(content));
+        next.fit(provider).await?;
+
+        let payload = Payload {
+            model: next.model.clone(),
+            messages: next.to_messages(),
+            ..Default::default()
+        };
+        let reply = provider.complete(payload).await?.into_message()?;
+
+        next.push(reply.clone());
+        *self = next;
+        Ok(reply)
+    }
+
+    async fn fit(&mut self, provider: &dyn ChatProvider) -> Result<(), OpenAiError> {
+        let removed = self.trim();
+        if removed.is_empty() || self.strategy != TrimStrategy::Summarize {
+            return Ok(());
+        }
+
+        let mut transcript = String::new();
+        if let Some(summary) = &self.summary {
+            transcript.push_str(&format!("Earlier summary: {}\n", summary));
+        }
+        for message in &removed {
+            transcript.push_str(&format!("{}: {}\n", message.role, message.content));
+        }
+
+        let payload = Payload {
+            model: self.model.clone(),
+            messages: vec![
+                Message::system(
+                    "Summarize this conversation in a few sentences, keeping names, facts and decisions \
+                     that later messages may refer to.",
+                ),
+                Message::user(transcript),
+            ],
+            ..Default::default()
+        };
+        let summary = provider.complete(payload).await?.into_message()?;
+        self.summary = Some(summary.content.text());
+
+        // the summary takes room too, so trim again without summarizing a second time
+        self.trim();
+        Ok(())
+    }
+}
+
+// Context window sizes in tokens; unknown and local models get a conservative default.
+pub fn context_window(model: &ChatModel) -> usize {
+    match model {
+        ChatModel::Gpt3Turbo => 16_385,
+        ChatModel::GPT4 => 8_192,
+        ChatModel::Gpt4Turbo | ChatModel::Gpt4o | ChatModel::Gpt4oMini => 128_000,
+        ChatModel::Gpt3TurboInstruct => 4_096,
+        ChatModel::Babbage002 | ChatModel::Davinci002 => 16_384,
+        ChatModel::Custom(_) => 8_192,
+    }
+}
+
+// A rough token count without a tokenizer: English averages about four characters per
+// token with the cl100k vocabulary and a little more with gpt-4o's larger one.
+pub fn estimate_tokens(model: &ChatModel, text: &str) -> usize {
+    let chars_per_token = match model {
+        ChatModel::Gpt4o | ChatModel::Gpt4oMini => 4.4,
+        _ => 4.0,
+    };
+    (text.chars().count() as f64 / chars_per_token).ceil() as usize
+}
+
+pub fn estimate_message_tokens(model: &ChatModel, message: &Message) -> usize {
+    let content = match &message.content {
+        Content::Text(text) => estimate_tokens(model, text),
+        Content::Parts(parts) => parts
+            .iter()
+            .map(|part| match part {
+                ContentPart::Text { text } => estimate_tokens(model, text),
+                ContentPart::ImageUrl { .. } => IMAGE_TOKENS,
+            })
+            .sum(),
+    };
+    let tool_calls: usize = message
+        .tool_calls
+        .iter()
+        .flatten()
+        .map(|call| estimate_tokens(model, &call.function.name) + estimate_tokens(model, &call.function.arguments))
+        .sum();
+
+    MESSAGE_OVERHEAD + content + tool_calls
+}
@@ -0,0 +1,180 @@
+use reqwest::Method;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+use crate::client::{default_client, OpenAiClient};
+use crate::error::OpenAiError;
+
+// The API accepts at most this many inputs per request.
+pub const MAX_BATCH_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub enum EmbeddingModel {
+    #[default]
+    TextEmbedding3Small,
+    TextEmbedding3Large,
+    Ada002,
+    Custom(String),
+}
+
+impl EmbeddingModel {
+    pub fn name(&self) -> &str {
+        match self {
+            EmbeddingModel::TextEmbedding3Small => "text-embedding-3-small",
+            EmbeddingModel::TextEmbedding3Large => "text-embedding-3-large",
+            EmbeddingModel::Ada002 => "text-embedding-ada-002",
+            EmbeddingModel::Custom(name) => name,
+        }
+    }
+}
+
+impl From<&str> for EmbeddingModel {
+    fn from(name: &str) -> Self {
+        match name {
+            "text-embedding-3-small" => EmbeddingModel::TextEmbedding3Small,
+            "text-embedding-3-large" => EmbeddingModel::TextEmbedding3Large,
+            "text-embedding-ada-002" => EmbeddingModel::Ada002,
+            other => EmbeddingModel::Custom(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for EmbeddingModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl Serialize for EmbeddingModel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for EmbeddingModel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(EmbeddingModel::from(String::deserialize(deserializer)?.as_str()))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct EmbeddingRequest {
+    pub model: EmbeddingModel,
+    pub input: Vec<String>,
+    // shortens the vectors; only supported by the text-embedding-3 models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EmbeddingResponse {
+    pub data: Vec<Embedding>,
+    pub model: EmbeddingModel,
+    #[serde(default)]
+    pub usage: Option<EmbeddingUsage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Embedding {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl EmbeddingRequest {
+    pub fn new<S: Into<String>>(model: EmbeddingModel, input: Vec<S>) -> EmbeddingRequest {
+        EmbeddingRequest {
+            model,
+            input: input.into_iter().map(Into::into).collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl OpenAiClient {
+    // A single /v1/embeddings call; the returned data is ordered like the input.
+    pub async fn embeddings(&self, request: &EmbeddingRequest) -> Result<EmbeddingResponse, OpenAiError> {
+        let http_request = self.request(Method::POST, "embeddings").json(request);
+        let body = self.send(http_request).await?.text().await?;
+
+        let mut response = serde_json::from_str::<EmbeddingResponse>(&body)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing embeddings: {}", e)))?;
+        response.data.sort_by_key(|embedding| embedding.index);
+        if let Some(usage) = response.usage {
+            crate::usage::tracker().record(response.model.name(), usage.prompt_tokens, 0);
+        }
+
+        if response.data.len() != request.input.len() {
+            return Err(OpenAiError::MalformedResponse(format!(
+                "Expected {} embeddings, got {}",
+                request.input.len(),
+                response.data.len()
+            )));
+        }
+        Ok(response)
+    }
+
+    // Embeds any number of inputs, splitting them over as many requests as needed.
+    pub async fn embed(&self, request: EmbeddingRequest) -> Result<Vec<Vec<f32>>, OpenAiError> {
+        self.embed_batched(request, MAX_BATCH_SIZE).await
+    }
+
+    pub async fn embed_batched(&self, request: EmbeddingRequest, batch_size: usize) -> Result<Vec<Vec<f32>>, OpenAiError> {
+        let EmbeddingRequest { model, input, dimensions, user } = request;
+        let mut vectors = Vec::with_capacity(input.len());
+
+        // each request carries only its own slice of the input, not a copy of all of it
+        for batch in input.chunks(batch_size.clamp(1, MAX_BATCH_SIZE)) {
+            let batch_request = EmbeddingRequest {
+                model: model.clone(),
+                input: batch.to_vec(),
+                dimensions,
+                user: user.clone(),
+            };
+            let response = self.embeddings(&batch_request).await?;
+            vectors.extend(response.data.into_iter().map(|embedding| embedding.embedding));
+        }
+        Ok(vectors)
+    }
+}
+
+pub async fn embed(request: EmbeddingRequest) -> Result<Vec<Vec<f32>>, OpenAiError> {
+    default_client()?.embed(request).await
+}
+
+// Returns 0.0 for mismatched lengths or zero vectors rather than NaN.
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let (mut dot, mut norm_a, mut norm_b) = (0.0f32, 0.0f32, 0.0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+// Indices of the candidates ordered from most to least similar to the query, with their scores.
+pub fn rank_by_similarity(query: &[f32], candidates: &[Vec<f32>]) -> Vec<(usize, f32)> {
+    let mut scores: Vec<(usize, f32)> = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, candidate)| (i, cosine_similarity(query, candidate)))
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    scores
+}
@@ -0,0 +1,156 @@
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+
+// The `error` object the API returns alongside a non-success status.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ApiError {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub param: Option<String>,
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiErrorBody {
+    error: ApiError,
+}
+
+impl ApiError {
+    // Parses an error body, falling back to the raw text when it isn't the usual shape.
+    pub fn from_body(body: &str) -> ApiError {
+        match serde_json::from_str::<ApiErrorBody>(body) {
+            Ok(parsed) => parsed.error,
+            Err(_) => ApiError {
+                message: body.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn string_or_number<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(match value {
+        Some(serde_json::Value::String(s)) => Some(s),
+        Some(serde_json::Value::Null) | None => None,
+        Some(other) => Some(other.to_string()),
+    })
+}
+
+#[derive(Debug)]
+pub enum OpenAiError {
+    MissingApiKey,
+    // a setting is missing or invalid, e.g. an unknown provider name
+    Config(String),
+    // rejected before sending, e.g. a temperature out of range
+    InvalidRequest(String),
+    // a prompt template was rendered without values for these placeholders
+    MissingVariables { template: String, missing: Vec<String> },
+    // the model isn't served by the endpoint it was sent to
+    WrongEndpoint { model: String, endpoint: &'static str },
+    Transport(reqwest::Error),
+    Api { status: u16, error: ApiError },
+    RateLimited { retry_after: Option<Duration>, error: ApiError },
+    MalformedResponse(String),
+    Runtime(std::io::Error),
+    ToolRoundsExceeded(usize),
+    // the model answered, but not with the JSON that was asked for
+    InvalidOutput { content: String, error: serde_json::Error },
+    // reading or writing a local file failed, e.g. a saved conversation or an audio file
+    Storage(std::io::Error),
+    // the moderation policy refused the input, or the reply when output is true
+    ContentFlagged { categories: Vec<String>, output: bool },
+    // a whole batch (by batch id) or one of its requests (by custom_id) got no usable result
+    BatchFailed { id: String, errors: Vec<ApiError> },
+}
+
+impl OpenAiError {
+    // Turns a non-success response into the matching error variant.
+    pub async fn from_response(response: reqwest::Response) -> OpenAiError {
+        let status = response.status().as_u16();
+        let retry_after = crate::retry::delay_from_headers(response.headers());
+
+        let error = match response.text().await {
+            Ok(body) => ApiError::from_body(&body),
+            Err(e) => return OpenAiError::Transport(e),
+        };
+
+        if status == 429 {
+            OpenAiError::RateLimited { retry_after, error }
+        } else {
+            OpenAiError::Api { status, error }
+        }
+    }
+}
+
+impl fmt::Display for OpenAiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenAiError::MissingApiKey => write!(f, "OPENAI_API_KEY not set"),
+            OpenAiError::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+            OpenAiError::InvalidRequest(msg) => write!(f, "Invalid request: {}", msg),
+            OpenAiError::MissingVariables { template, missing } => {
+                write!(f, "Template {} is missing values for: {}", template, missing.join(", "))
+            }
+            OpenAiError::WrongEndpoint { model, endpoint } => {
+                write!(f, "Model {} is not served by {}", model, endpoint)
+            }
+            OpenAiError::Transport(e) => write!(f, "Failed to send request: {}", e),
+            OpenAiError::Api { status, error } => {
+                write!(f, "API Request Failed ({}): {}", status, error.message)?;
+                if let Some(code) = &error.code {
+                    write!(f, " [{}]", code)?;
+                }
+                Ok(())
+            }
+            OpenAiError::RateLimited { retry_after, error } => {
+                write!(f, "Rate limited: {}", error.message)?;
+                if let Some(wait) = retry_after {
+                    write!(f, " (retry after {:.1}s)", wait.as_secs_f64())?;
+                }
+                Ok(())
+            }
+            OpenAiError::MalformedResponse(msg) => write!(f, "Malformed response: {}", msg),
+            OpenAiError::Runtime(e) => write!(f, "Failed to create runtime: {}", e),
+            OpenAiError::ToolRoundsExceeded(rounds) => {
+                write!(f, "Model was still calling tools after {} rounds", rounds)
+            }
+            OpenAiError::InvalidOutput { error, .. } => {
+                write!(f, "Model output did not match the expected format: {}", error)
+            }
+            OpenAiError::Storage(e) => write!(f, "File access failed: {}", e),
+            OpenAiError::ContentFlagged { categories, output } => {
+                let side = if *output { "Reply" } else { "Input" };
+                write!(f, "{} refused by moderation: {}", side, categories.join(", "))
+            }
+            OpenAiError::BatchFailed { id, errors } => {
+                let messages: Vec<&str> = errors.iter().map(|error| error.message.as_str()).collect();
+                write!(f, "Batch {} failed: {}", id, messages.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for OpenAiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OpenAiError::Transport(e) => Some(e),
+            OpenAiError::Runtime(e) => Some(e),
+            OpenAiError::InvalidOutput { error, .. } => Some(error),
+            OpenAiError::Storage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for OpenAiError {
+    fn from(e: reqwest::Error) -> Self {
+        OpenAiError::Transport(e)
+    }
+}
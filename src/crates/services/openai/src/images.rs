@@ -0,0 +1,316 @@
+use base64::Engine;
+use reqwest::multipart::{Form, Part};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::client::{default_client, OpenAiClient};
+use crate::error::OpenAiError;
+
+pub const DEFAULT_IMAGE_MODEL: &str = "dall-e-3";
+// the only model the edits endpoint accepts
+pub const EDIT_IMAGE_MODEL: &str = "dall-e-2";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageSize {
+    #[serde(rename = "256x256")]
+    Small,
+    #[serde(rename = "512x512")]
+    Medium,
+    #[default]
+    #[serde(rename = "1024x1024")]
+    Large,
+    #[serde(rename = "1792x1024")]
+    Wide,
+    #[serde(rename = "1024x1792")]
+    Tall,
+}
+
+impl ImageSize {
+    // Which sizes a model can produce; unknown models are left for the API to judge.
+    fn supported_by(self, model: &str) -> bool {
+        match model {
+            "dall-e-2" => matches!(self, ImageSize::Small | ImageSize::Medium | ImageSize::Large),
+            "dall-e-3" => matches!(self, ImageSize::Large | ImageSize::Wide | ImageSize::Tall),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for ImageSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = match self {
+            ImageSize::Small => "256x256",
+            ImageSize::Medium => "512x512",
+            ImageSize::Large => "1024x1024",
+            ImageSize::Wide => "1792x1024",
+            ImageSize::Tall => "1024x1792",
+        };
+        write!(f, "{}", size)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageQuality {
+    #[default]
+    Standard,
+    // finer detail, dall-e-3 only
+    Hd,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageStyle {
+    Vivid,
+    Natural,
+}
+
+// How generated images are returned. URLs expire an hour after generation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    #[default]
+    Url,
+    B64Json,
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageFormat::Url => write!(f, "url"),
+            ImageFormat::B64Json => write!(f, "b64_json"),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ImageRequest {
+    pub model: String,
+    pub prompt: String,
+    // 1 to 10; dall-e-3 only makes one image per request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<ImageSize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality: Option<ImageQuality>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style: Option<ImageStyle>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ImageFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl ImageRequest {
+    pub fn new<S: Into<String>>(prompt: S) -> ImageRequest {
+        ImageRequest {
+            model: DEFAULT_IMAGE_MODEL.to_string(),
+            prompt: prompt.into(),
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), OpenAiError> {
+        check_count(&self.model, self.n)?;
+        check_size(&self.model, self.size)?;
+        if self.quality == Some(ImageQuality::Hd) && self.model != "dall-e-3" {
+            return Err(OpenAiError::InvalidRequest(format!("hd quality isn't available for {}", self.model)));
+        }
+        if self.style.is_some() && self.model != "dall-e-3" {
+            return Err(OpenAiError::InvalidRequest(format!("style isn't available for {}", self.model)));
+        }
+        Ok(())
+    }
+}
+
+// Changes part of an existing image. Transparent areas of the mask, or of the image itself
+// when there is no mask, are the ones redrawn. Both must be square PNGs under 4MB.
+#[derive(Debug, Clone, Default)]
+pub struct ImageEditRequest {
+    pub image: PathBuf,
+    pub mask: Option<PathBuf>,
+    // describes the whole result, not just the edited area
+    pub prompt: String,
+    pub model: String,
+    pub n: Option<u8>,
+    pub size: Option<ImageSize>,
+    pub response_format: Option<ImageFormat>,
+    pub user: Option<String>,
+}
+
+impl ImageEditRequest {
+    pub fn new<P: Into<PathBuf>, S: Into<String>>(image: P, prompt: S) -> ImageEditRequest {
+        ImageEditRequest {
+            image: image.into(),
+            prompt: prompt.into(),
+            model: EDIT_IMAGE_MODEL.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), OpenAiError> {
+        check_count(&self.model, self.n)?;
+        check_size(&self.model, self.size)
+    }
+
+    async fn form(&self) -> Result<Form, OpenAiError> {
+        let mut form = Form::new()
+            .part("image", file_part(&self.image).await?)
+            .text("prompt", self.prompt.clone())
+            .text("model", self.model.clone());
+        if let Some(mask) = &self.mask {
+            form = form.part("mask", file_part(mask).await?);
+        }
+        if let Some(n) = self.n {
+            form = form.text("n", n.to_string());
+        }
+        if let Some(size) = self.size {
+            form = form.text("size", size.to_string());
+        }
+        if let Some(format) = self.response_format {
+            form = form.text("response_format", format.to_string());
+        }
+        if let Some(user) = &self.user {
+            form = form.text("user", user.clone());
+        }
+        Ok(form)
+    }
+}
+
+fn check_count(model: &str, n: Option<u8>) -> Result<(), OpenAiError> {
+    let max = if model == "dall-e-3" { 1 } else { 10 };
+    match n {
+        Some(n) if n == 0 || n > max => Err(OpenAiError::InvalidRequest(format!(
+            "n must be between 1 and {} for {}, got {}",
+            max, model, n
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn check_size(model: &str, size: Option<ImageSize>) -> Result<(), OpenAiError> {
+    match size {
+        Some(size) if !size.supported_by(model) => Err(OpenAiError::InvalidRequest(format!(
+            "{} can't make {} images",
+            model, size
+        ))),
+        _ => Ok(()),
+    }
+}
+
+async fn file_part(path: &Path) -> Result<Part, OpenAiError> {
+    let data = tokio::fs::read(path).await.map_err(OpenAiError::Storage)?;
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "image.png".to_string());
+    Ok(Part::bytes(data).file_name(file_name).mime_str("image/png")?)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImagesResponse {
+    #[serde(default)]
+    pub created: u64,
+    pub data: Vec<GeneratedImage>,
+}
+
+// Exactly one of url and b64_json is set, depending on the requested format.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct GeneratedImage {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub b64_json: Option<String>,
+    // dall-e-3 rewrites prompts before drawing; this is what it actually used
+    #[serde(default)]
+    pub revised_prompt: Option<String>,
+}
+
+impl GeneratedImage {
+    // The decoded PNG, when the image was returned inline.
+    pub fn bytes(&self) -> Result<Option<Vec<u8>>, OpenAiError> {
+        let Some(data) = &self.b64_json else {
+            return Ok(None);
+        };
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .map(Some)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error decoding image: {}", e)))
+    }
+}
+
+impl OpenAiClient {
+    pub async fn generate_images(&self, request: &ImageRequest) -> Result<ImagesResponse, OpenAiError> {
+        request.validate()?;
+        // every call creates (and bills) new images, so never resend blindly
+        let http_request = self.request(Method::POST, "images/generations").json(request);
+        let body = self.send_with(http_request, false).await?.text().await?;
+        parse_images(&body)
+    }
+
+    pub async fn edit_images(&self, request: &ImageEditRequest) -> Result<ImagesResponse, OpenAiError> {
+        request.validate()?;
+        let response = self.send_multipart("images/edits", request.form().await?).await?;
+        let body = response.text().await?;
+        parse_images(&body)
+    }
+
+    // The image's PNG bytes, downloading them when the API returned a URL.
+    pub async fn image_bytes(&self, image: &GeneratedImage) -> Result<Vec<u8>, OpenAiError> {
+        if let Some(bytes) = image.bytes()? {
+            return Ok(bytes);
+        }
+        let Some(url) = &image.url else {
+            return Err(OpenAiError::MalformedResponse("Image has neither url nor b64_json".to_string()));
+        };
+        // a presigned storage URL, so it's fetched without the API credentials
+        let response = self.http.get(url).send().await?.error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    pub async fn save_image<P: AsRef<Path>>(&self, image: &GeneratedImage, path: P) -> Result<(), OpenAiError> {
+        let bytes = self.image_bytes(image).await?;
+        if let Some(dir) = path.as_ref().parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(OpenAiError::Storage)?;
+        }
+        tokio::fs::write(path, bytes).await.map_err(OpenAiError::Storage)
+    }
+
+    // Generates a single image straight to a file, e.g. a game sprite. Returns what was
+    // generated, so the revised prompt can be shown or kept.
+    pub async fn generate_image_to_file<P: AsRef<Path>>(
+        &self,
+        request: &ImageRequest,
+        path: P,
+    ) -> Result<GeneratedImage, OpenAiError> {
+        let request = ImageRequest {
+            n: Some(1),
+            response_format: Some(ImageFormat::B64Json),
+            ..request.clone()
+        };
+        let response = self.generate_images(&request).await?;
+        let image = response
+            .data
+            .into_iter()
+            .next()
+            .ok_or_else(|| OpenAiError::MalformedResponse("No image was generated".to_string()))?;
+        self.save_image(&image, path).await?;
+        Ok(image)
+    }
+}
+
+fn parse_images(body: &str) -> Result<ImagesResponse, OpenAiError> {
+    serde_json::from_str::<ImagesResponse>(body)
+        .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing images: {}", e)))
+}
+
+pub async fn generate_images(request: &ImageRequest) -> Result<ImagesResponse, OpenAiError> {
+    default_client()?.generate_images(request).await
+}
+
+pub async fn generate_image_to_file<P: AsRef<Path>>(request: &ImageRequest, path: P) -> Result<GeneratedImage, OpenAiError> {
+    default_client()?.generate_image_to_file(request, path).await
+}
@@ -1,16 +1,1435 @@
+pub mod anthropic;
+pub mod audio;
+pub mod batch;
+pub mod bulk;
+pub mod cache;
 pub mod chat;
+pub mod client;
+pub mod completions;
+pub mod content;
+pub mod conversation;
+pub mod embeddings;
+pub mod error;
+pub mod images;
+pub mod logprobs;
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock;
+pub mod models;
+pub mod moderation;
+pub mod provider;
+pub mod retry;
+pub mod store;
+pub mod structured;
+pub mod templates;
+pub mod tools;
+pub mod usage;
+
+pub use client::{ClientConfig, OpenAiClient};
+pub use error::OpenAiError;
+pub use provider::ChatProvider;
+pub use retry::RetryPolicy;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures_util::StreamExt;
+    use mock::{MockResponse, MockServer, TempPath};
+    use serde_json::json;
 
     #[test]
     fn chat_can_prompt() {
-        let res = chat::prompt_sync(
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat("POLO!"));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        let res = rt.block_on(server.client().prompt(
             String::from("MARCO!"),
-            vec![]   
+            vec![]
+        ));
+
+        assert_eq!(res.unwrap().content, "POLO!");
+    }
+
+    #[test]
+    fn sse_decoder_handles_split_events() {
+        let mut decoder = chat::SseDecoder::default();
+
+        assert!(decoder.push(b"data: {\"a\"").is_empty());
+        assert_eq!(decoder.push(b":1}\r\n\r\n: keep-alive\n\ndata: [DONE]\n\n"), vec!["{\"a\":1}", "[DONE]"]);
+        assert_eq!(decoder.push(b"data: tail"), Vec::<String>::new());
+        assert_eq!(decoder.finish(), Some("tail".to_string()));
+    }
+
+    #[tokio::test]
+    async fn chat_stream_yields_tokens_and_message() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat_stream(&["PO", "LO!"]));
+
+        let mut stream = server.client().prompt_stream(String::from("MARCO!"), vec![]).await.unwrap();
+
+        let mut tokens = vec![];
+        while let Some(token) = stream.next().await {
+            tokens.push(token.unwrap());
+        }
+
+        assert_eq!(tokens, vec!["PO", "LO!"]);
+        let message = stream.message();
+        assert_eq!(message.role, chat::Role::Assistant);
+        assert_eq!(message.content, "POLO!");
+        assert_eq!(stream.finish_reason(), Some(chat::FinishReason::Stop));
+        assert_eq!(server.requests()[0].json()["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn chat_stream_reports_malformed_chunks() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::new(200, "text/event-stream", b"data: not json\n\n".to_vec()));
+
+        let res = server.client().prompt_stream(String::from("MARCO!"), vec![]).await.unwrap().collect_message().await;
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn api_error_parses_body_or_falls_back_to_text() {
+        let error = error::ApiError::from_body(
+            r#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","param":null,"code":"invalid_api_key"}}"#,
+        );
+        assert_eq!(error.kind.as_deref(), Some("invalid_request_error"));
+        assert_eq!(error.code.as_deref(), Some("invalid_api_key"));
+
+        let error = error::ApiError::from_body("Bad Gateway");
+        assert_eq!(error.message, "Bad Gateway");
+        assert_eq!(error.code, None);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_status_maps_to_rate_limited() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::rate_limited(2));
+        let response = reqwest::get(server.url()).await.unwrap();
+
+        match OpenAiError::from_response(response).await {
+            OpenAiError::RateLimited { retry_after, error } => {
+                assert_eq!(retry_after, Some(std::time::Duration::from_secs(2)));
+                assert_eq!(error.code.as_deref(), Some("rate_limit_exceeded"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_prompt_sends_configured_headers() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat("POLO!"));
+        let client = OpenAiClient::new(ClientConfig {
+            base_url: format!("{}/v1/", server.url()),
+            api_key: Some("sk-test".to_string()),
+            organization: Some("org-123".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let message = client.prompt(String::from("MARCO!"), vec![]).await.unwrap();
+        assert_eq!(message.content, "POLO!");
+
+        let request = &server.requests()[0];
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/v1/chat/completions"));
+        assert_eq!(request.header("authorization"), Some("Bearer sk-test"));
+        assert_eq!(request.header("openai-organization"), Some("org-123"));
+        assert_eq!(request.json()["messages"][0]["content"], "MARCO!");
+    }
+
+    #[test]
+    fn azure_config_uses_api_key_header_and_version() {
+        let config = ClientConfig::azure("https://res.openai.azure.com/", "gpt4", "2024-02-01", "key");
+        let client = OpenAiClient::new(config).unwrap();
+
+        assert_eq!(client.url("chat/completions"), "https://res.openai.azure.com/openai/deployments/gpt4/chat/completions");
+        assert_eq!(client.config().auth, client::AuthScheme::Header("api-key".to_string()));
+        assert_eq!(client.config().query, vec![("api-version".to_string(), "2024-02-01".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn streams_ask_for_usage_only_from_openai() {
+        use chat::{ChatModel, Payload};
+
+        let server = MockServer::start();
+        let local = OpenAiClient::new(ClientConfig::local(&server.url())).unwrap();
+        let azure = OpenAiClient::new(ClientConfig::azure(&server.url(), "gpt4", "2024-02-01", "key")).unwrap();
+        for client in [&local, &azure] {
+            server.enqueue(MockResponse::chat_stream(&["Hi"]));
+            server.enqueue(MockResponse::chat_stream(&["Hi"]));
+            let payload = |model: ChatModel| Payload::builder().model(model).user("Hello").build().unwrap();
+            let mut stream = client.chat_stream(payload(ChatModel::Gpt4oMini)).await.unwrap();
+            while stream.next().await.is_some() {}
+            client.chat_stream(payload(ChatModel::Gpt3TurboInstruct)).await.unwrap();
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests.iter().all(|request| request.json().get("stream_options").is_none()));
+        assert!(ClientConfig::default().stream_usage && !ClientConfig::local("http://localhost:11434/v1").stream_usage);
+    }
+
+    #[test]
+    fn parses_rate_limit_reset_durations() {
+        use std::time::Duration;
+
+        assert_eq!(retry::parse_reset_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(retry::parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(retry::parse_reset_duration("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(retry::parse_reset_duration("1h2m3.5s"), Some(Duration::from_secs_f64(3723.5)));
+        assert_eq!(retry::parse_reset_duration("soon"), None);
+        assert_eq!(retry::parse_reset_duration("12"), None);
+        assert_eq!(retry::parse_reset_duration("1e400s"), None);
+    }
+
+    #[test]
+    fn invalid_retry_headers_are_ignored() {
+        use reqwest::header::{HeaderMap, HeaderValue};
+        use std::time::Duration;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("inf"));
+        assert_eq!(retry::delay_from_headers(&headers), None);
+
+        headers.insert("retry-after-ms", HeaderValue::from_static("1e400"));
+        headers.insert("retry-after", HeaderValue::from_static("-5"));
+        headers.insert("x-ratelimit-reset-requests", HeaderValue::from_static("2s"));
+        assert_eq!(retry::delay_from_headers(&headers), Some(Duration::from_secs(2)));
+
+        // the longest wait wins, whichever header it came from
+        headers.insert("retry-after-ms", HeaderValue::from_static("500"));
+        headers.insert("retry-after", HeaderValue::from_static("3"));
+        assert_eq!(retry::delay_from_headers(&headers), Some(Duration::from_secs(3)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(retry::delay_from_headers(&headers), Some(Duration::ZERO));
+        let later = httpdate::fmt_http_date(std::time::SystemTime::now() + Duration::from_secs(60));
+        headers.insert("retry-after", HeaderValue::from_str(&later).unwrap());
+        let wait = retry::delay_from_headers(&headers).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        use std::time::Duration;
+
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            jitter: false,
+            ..Default::default()
+        };
+
+        assert_eq!(policy.delay(1, None), Duration::from_millis(100));
+        assert_eq!(policy.delay(2, None), Duration::from_millis(200));
+        assert_eq!(policy.delay(3, None), Duration::from_millis(300));
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(5))), Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn client_retries_rate_limits_and_server_errors() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::rate_limited(0).with_header("retry-after-ms", "10"));
+        server.enqueue(MockResponse::new(502, "text/plain", b"Bad Gateway".to_vec()));
+        server.enqueue(MockResponse::chat("POLO!"));
+
+        let message = server.client().prompt(String::from("MARCO!"), vec![]).await.unwrap();
+
+        assert_eq!(message.content, "POLO!");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn client_does_not_replay_state_changing_requests_on_server_errors() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::error(500, "server_error", "internal", "oops"));
+        server.enqueue(MockResponse::json(200, json!({})));
+        let client = server.client();
+
+        let request = client.request(reqwest::Method::POST, "files").body("data");
+        let res = client.send_with(request, false).await;
+
+        assert!(matches!(res, Err(OpenAiError::Api { status: 500, .. })));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn tool_choice_serializes_like_the_api() {
+        use tools::ToolChoice;
+
+        assert_eq!(serde_json::to_string(&ToolChoice::Auto).unwrap(), r#""auto""#);
+        let named = serde_json::to_value(ToolChoice::Function("get_time".to_string())).unwrap();
+        assert_eq!(named, json!({ "type": "function", "function": { "name": "get_time" } }));
+        assert_eq!(serde_json::from_value::<ToolChoice>(named).unwrap(), ToolChoice::Function("get_time".to_string()));
+    }
+
+    #[tokio::test]
+    async fn run_tools_dispatches_handlers_until_the_model_answers() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::json(200, json!({
+            "model": "gpt-3.5-turbo",
+            "choices": [{ "index": 0, "finish_reason": "tool_calls", "message": { "role": "assistant", "content": null,
+                "tool_calls": [{ "id": "call_1", "type": "function", "function": { "name": "add", "arguments": "{\"a\":2,\"b\":3}" } }] } }]
+        })));
+        server.enqueue(MockResponse::chat("2 + 3 = 5"));
+
+        let mut registry = tools::ToolRegistry::new();
+        registry.register(
+            tools::Tool::function("add", "Adds two numbers", json!({
+                "type": "object",
+                "properties": { "a": { "type": "number" }, "b": { "type": "number" } },
+                "required": ["a", "b"]
+            })),
+            |args| Ok((args["a"].as_f64().unwrap_or(0.0) + args["b"].as_f64().unwrap_or(0.0)).to_string()),
+        );
+
+        let added = server
+            .client()
+            .run_tools(
+                chat::Payload::builder().model(chat::ChatModel::Gpt4oMini).user("What is 2 + 3?"),
+                &registry,
+                4,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(added.len(), 3);
+        assert_eq!(added[0].tool_calls.as_ref().unwrap()[0].function.name, "add");
+        assert_eq!(added[1], chat::Message::tool("call_1", String::from("5")));
+        assert_eq!(added[2].content, "2 + 3 = 5");
+
+        let requests = server.requests();
+        assert_eq!(requests[0].json()["tools"][0]["function"]["name"], "add");
+        assert_eq!(requests[0].json()["tool_choice"], "auto");
+        assert_eq!(requests[0].json()["model"], "gpt-4o-mini");
+        assert_eq!(requests[1].json()["messages"][2]["tool_call_id"], "call_1");
+
+        server.enqueue(MockResponse::chat("Hello"));
+        let added = server
+            .client()
+            .run_tools(chat::Payload::builder().user("Hi"), &tools::ToolRegistry::new(), 1)
+            .await
+            .unwrap();
+        assert_eq!(added[0].content, "Hello");
+        let request = server.requests()[2].json();
+        assert!(request.get("tools").is_none() && request.get("tool_choice").is_none());
+    }
+
+    #[test]
+    fn unknown_tools_are_reported_back_to_the_model() {
+        let registry = tools::ToolRegistry::new();
+        let call = tools::ToolCall {
+            id: "call_9".to_string(),
+            kind: "function".to_string(),
+            function: tools::FunctionCall { name: "rm_rf".to_string(), arguments: "{}".to_string() },
+        };
+
+        assert_eq!(registry.call(&call).content, "error: unknown tool: rm_rf");
+    }
+
+    #[tokio::test]
+    async fn chat_stream_assembles_tool_calls() {
+        let body = [
+            r#"{"choices":[{"index":0,"delta":{"role":"assistant","tool_calls":[{"index":0,"id":"call_1","type":"function","function":{"name":"add","arguments":""}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"a\":"}}]}}]}"#,
+            r#"{"choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"2}"}}]}}]}"#,
+            "[DONE]",
+        ]
+        .iter()
+        .map(|data| format!("data: {}\n\n", data))
+        .collect::<String>();
+        let server = MockServer::start();
+        server.enqueue(MockResponse::new(200, "text/event-stream", body.into_bytes()));
+
+        let message = server
+            .client()
+            .prompt_stream(String::from("add"), vec![])
+            .await
+            .unwrap()
+            .collect_message()
+            .await
+            .unwrap();
+
+        let calls = message.tool_calls.unwrap();
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].function.arguments, r#"{"a":2}"#);
+    }
+
+    #[tokio::test]
+    async fn chat_returns_typed_completion() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::json(200, json!({
+            "id": "chatcmpl-123", "object": "chat.completion", "created": 1677652288, "model": "gpt-4o",
+            "system_fingerprint": "fp_44709d6fcb",
+            "choices": [
+                { "index": 0, "message": { "role": "assistant", "content": "POLO!" }, "logprobs": null, "finish_reason": "stop" },
+                { "index": 1, "message": { "role": "assistant", "content": "PO" }, "logprobs": null, "finish_reason": "length" }
+            ],
+            "usage": { "prompt_tokens": 9, "completion_tokens": 12, "total_tokens": 21 }
+        })));
+
+        let completion = server.client().chat(chat::Payload::default()).await.unwrap();
+
+        assert_eq!(completion.id, "chatcmpl-123");
+        assert_eq!(completion.model, chat::ChatModel::Gpt4o);
+        assert_eq!(completion.created, 1677652288);
+        assert_eq!(completion.system_fingerprint.as_deref(), Some("fp_44709d6fcb"));
+        assert_eq!(completion.usage.unwrap().total_tokens, 21);
+        assert_eq!(completion.choices.len(), 2);
+        assert_eq!(completion.choices[1].finish_reason, Some(chat::FinishReason::Length));
+        assert!(completion.is_truncated());
+        assert_eq!(completion.first_message().unwrap().content, "POLO!");
+    }
+
+    #[test]
+    fn message_content_supports_text_and_image_parts() {
+        use chat::{ContentPart, Message, Role};
+
+        let path = TempPath::new("openai-content.png");
+        std::fs::write(&path, [0x89, b'P', b'N', b'G']).unwrap();
+        let message = Message::user(vec![ContentPart::text("What is this?"), ContentPart::image_file(&path).unwrap()]);
+
+        let value = serde_json::to_value(&message).unwrap();
+        assert_eq!(value["role"], "user");
+        assert_eq!(value["content"][0], json!({ "type": "text", "text": "What is this?" }));
+        assert_eq!(value["content"][1]["type"], "image_url");
+        assert_eq!(value["content"][1]["image_url"]["url"], "data:image/png;base64,iVBORw==");
+
+        let parsed: Message = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed, message);
+        assert_eq!(parsed.content.to_string(), "What is this?");
+
+        let plain: Message = serde_json::from_str(r#"{"role":"tool","content":"5","tool_call_id":"call_1"}"#).unwrap();
+        assert_eq!(plain.role, Role::Tool);
+        assert_eq!(plain.content, "5");
+        assert!(ContentPart::image_file("notes.txt").is_err());
+    }
+
+    #[test]
+    fn chat_model_round_trips_through_serde() {
+        use chat::ChatModel;
+
+        assert_eq!(serde_json::to_string(&ChatModel::Gpt4Turbo).unwrap(), r#""gpt-4-turbo""#);
+        assert_eq!(serde_json::from_str::<ChatModel>(r#""gpt-4o-mini""#).unwrap(), ChatModel::Gpt4oMini);
+
+        let local = serde_json::from_str::<ChatModel>(r#""llama3:8b""#).unwrap();
+        assert_eq!(local, ChatModel::Custom("llama3:8b".to_string()));
+        assert_eq!(serde_json::to_string(&local).unwrap(), r#""llama3:8b""#);
+    }
+
+    #[tokio::test]
+    async fn list_models_returns_metadata() {
+        let server = MockServer::start();
+        server.enqueue_for("models", MockResponse::json(200, json!({ "object": "list", "data": [
+            { "id": "gpt-4o", "object": "model", "created": 1715367049, "owned_by": "system" },
+            { "id": "ft:gpt-4o-mini:acme::abc123", "object": "model", "created": 1721172741, "owned_by": "acme" }
+        ] })));
+
+        let models = server.client().list_models().await.unwrap();
+
+        assert_eq!(server.requests()[0].method, "GET");
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].model(), chat::ChatModel::Gpt4o);
+        assert_eq!(models[1].owned_by, "acme");
+        assert_eq!(models[1].model(), chat::ChatModel::Custom("ft:gpt-4o-mini:acme::abc123".to_string()));
+    }
+
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct Verdict {
+        safe: bool,
+        reason: String,
+    }
+
+    #[tokio::test]
+    async fn prompt_json_deserializes_into_the_requested_type() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat(r#"{"safe":true,"reason":"read only"}"#));
+        let schema = structured::JsonSchemaFormat::new("verdict", json!({
+            "type": "object",
+            "properties": { "safe": { "type": "boolean" }, "reason": { "type": "string" } },
+            "required": ["safe", "reason"]
+        }));
+
+        let verdict: Verdict = server
+            .client()
+            .prompt_json(String::from("Is `ls -la` safe?"), vec![], Some(schema))
+            .await
+            .unwrap();
+
+        assert_eq!(verdict, Verdict { safe: true, reason: "read only".to_string() });
+        let response_format = &server.requests()[0].json()["response_format"];
+        assert_eq!(response_format["type"], "json_schema");
+        assert_eq!(response_format["json_schema"]["name"], "verdict");
+        // gpt-3.5-turbo can't do structured outputs, so schema requests default to a model that can
+        assert_eq!(server.requests()[0].json()["model"], "gpt-4o-mini");
+
+        server.enqueue(MockResponse::chat(r#"{"safe":false,"reason":"deletes files"}"#));
+        let schema = structured::JsonSchemaFormat::new("verdict", json!({ "type": "object" }));
+        let payload = chat::Payload::builder().model(chat::ChatModel::Gpt4o).user("Is `rm -rf /` safe?");
+        let verdict: Verdict = server.client().chat_json(payload, Some(schema)).await.unwrap();
+
+        assert!(!verdict.safe);
+        assert_eq!(server.requests()[1].json()["model"], "gpt-4o");
+    }
+
+    #[tokio::test]
+    async fn prompt_json_reports_output_that_does_not_validate() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat(r#"{"safe":"maybe"}"#));
+
+        let res = server.client().prompt_json::<Verdict>(String::from("Is `rm -rf /` safe?"), vec![], None).await;
+
+        match res {
+            Err(OpenAiError::InvalidOutput { content, .. }) => assert_eq!(content, r#"{"safe":"maybe"}"#),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let request = server.requests()[0].json();
+        assert_eq!(request["response_format"], json!({ "type": "json_object" }));
+        assert_eq!(request["messages"][0]["content"], "Respond with a single JSON object.");
+    }
+
+    #[cfg(feature = "schemars")]
+    #[test]
+    fn json_schema_format_is_derived_from_the_type() {
+        #[derive(schemars::JsonSchema)]
+        #[allow(dead_code)]
+        struct Verdict {
+            safe: bool,
+            reason: String,
+        }
+
+        let format = structured::JsonSchemaFormat::for_type::<Verdict>();
+
+        assert_eq!(format.name, "Verdict");
+        assert_eq!(format.schema["properties"]["safe"]["type"], "boolean");
+        assert!(format.schema.get("$schema").is_none());
+    }
+
+    #[tokio::test]
+    async fn embed_batches_inputs_and_keeps_their_order() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::json(200, json!({ "object": "list", "model": "text-embedding-3-small", "data": [
+            { "object": "embedding", "index": 1, "embedding": [0.0, 1.0] },
+            { "object": "embedding", "index": 0, "embedding": [1.0, 0.0] }
+        ], "usage": { "prompt_tokens": 4, "total_tokens": 4 } })));
+        server.enqueue(MockResponse::json(200, json!({ "object": "list", "model": "text-embedding-3-small", "data": [
+            { "object": "embedding", "index": 0, "embedding": [0.6, 0.8] }
+        ] })));
+
+        let mut request = embeddings::EmbeddingRequest::new(embeddings::EmbeddingModel::default(), vec!["git log", "ls", "cargo test"]);
+        request.dimensions = Some(2);
+        let vectors = server.client().embed_batched(request, 2).await.unwrap();
+
+        assert_eq!(vectors, vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![0.6, 0.8]]);
+        let requests = server.requests();
+        assert_eq!(requests[0].json()["input"], json!(["git log", "ls"]));
+        assert_eq!(requests[0].json()["dimensions"], 2);
+        assert_eq!(requests[1].json()["input"], json!(["cargo test"]));
+    }
+
+    #[test]
+    fn cosine_similarity_ranks_candidates() {
+        use embeddings::{cosine_similarity, rank_by_similarity};
+
+        assert!((cosine_similarity(&[1.0, 0.0], &[1.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+
+        let ranked = rank_by_similarity(&[1.0, 0.0], &[vec![0.0, 1.0], vec![0.6, 0.8], vec![1.0, 0.1]]);
+        assert_eq!(ranked.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![2, 1, 0]);
+    }
+
+    #[tokio::test]
+    async fn mock_server_replays_cassettes_by_request_body() {
+        let cassette = mock::Cassette {
+            interactions: ["MARCO!", "PING"]
+                .iter()
+                .zip(["POLO!", "PONG"])
+                .map(|(question, answer)| {
+                    let payload = chat::Payload {
+                        messages: vec![chat::Message::user(*question)],
+                        ..Default::default()
+                    };
+                    mock::Interaction {
+                        request: mock::RecordedRequest {
+                            method: "POST".to_string(),
+                            path: "/chat/completions".to_string(),
+                            query: String::new(),
+                            headers: vec![],
+                            body: serde_json::to_string(&payload).unwrap(),
+                        },
+                        response: mock::RecordedResponse::from(&MockResponse::chat(answer)),
+                    }
+                })
+                .collect(),
+        };
+        let path = TempPath::new("openai-cassette.json");
+        cassette.save(&path).unwrap();
+
+        let server = MockServer::replay_file(&path).unwrap();
+        let client = server.client();
+
+        assert_eq!(client.prompt(String::from("PING"), vec![]).await.unwrap().content, "PONG");
+        assert_eq!(client.prompt(String::from("MARCO!"), vec![]).await.unwrap().content, "POLO!");
+        assert!(matches!(
+            client.prompt(String::from("MARCO!"), vec![]).await,
+            Err(OpenAiError::Api { status: 500, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn mock_server_records_upstream_interactions() {
+        let upstream = MockServer::start();
+        upstream.enqueue(MockResponse::chat("POLO!"));
+        let path = TempPath::new("openai-recording.json");
+
+        let recorder = MockServer::record(upstream.config(), &path).unwrap();
+        let message = recorder.client().prompt(String::from("MARCO!"), vec![]).await.unwrap();
+        assert_eq!(message.content, "POLO!");
+        assert_eq!(upstream.requests()[0].header("authorization"), Some("Bearer sk-mock"));
+
+        let replay = MockServer::replay_file(&path).unwrap();
+        let message = replay.client().prompt(String::from("MARCO!"), vec![]).await.unwrap();
+        assert_eq!(message.content, "POLO!");
+    }
+
+    #[test]
+    fn conversation_trims_whole_turns_to_fit_the_budget() {
+        use chat::{ChatModel, Message};
+        use conversation::Conversation;
+
+        let mut conversation = Conversation::new(ChatModel::Gpt3Turbo, Some(String::from("Be brief."))).with_budget(60, 10);
+        for turn in 0..4 {
+            conversation.push(Message::user(format!("question {} {}", turn, "x".repeat(40))));
+            conversation.push(Message::assistant(format!("answer {}", turn)));
+        }
+        assert!(conversation.is_over_budget());
+
+        let removed = conversation.trim();
+
+        assert!(!conversation.is_over_budget());
+        assert_eq!(removed.len() % 2, 0);
+        assert!(conversation.messages[0].content.text().starts_with("question"));
+        assert_eq!(conversation.messages.last().unwrap().content, "answer 3");
+        assert_eq!(conversation.to_messages()[0].content, "Be brief.");
+    }
+
+    #[tokio::test]
+    async fn conversation_appends_both_sides_and_summarizes_old_turns() {
+        use chat::ChatModel;
+        use conversation::{Conversation, TrimStrategy};
+
+        let server = MockServer::start();
+        let client = server.client();
+        let mut conversation = Conversation::new(ChatModel::Gpt4oMini, None)
+            .with_budget(30, 0)
+            .with_strategy(TrimStrategy::Summarize);
+
+        server.enqueue(MockResponse::chat("Hi Ada!"));
+        conversation.send(&client, "Hi, my name is Ada and I write Rust all day long.").await.unwrap();
+        assert_eq!(conversation.messages.len(), 2);
+
+        // a failed summary keeps the old turns and drops the unanswered question
+        let before = conversation.clone();
+        server.enqueue(MockResponse::error(400, "invalid_request_error", "invalid_value", "Bad request"));
+        assert!(conversation.send(&client, "What is my name? Please answer with my name only.").await.is_err());
+        assert_eq!(conversation, before);
+
+        server.enqueue(MockResponse::chat("The user is Ada, a Rust developer."));
+        server.enqueue(MockResponse::chat("Your name is Ada."));
+        let reply = conversation.send(&client, "What is my name? Please answer with my name only.").await.unwrap();
+
+        assert_eq!(reply.content, "Your name is Ada.");
+        assert_eq!(conversation.summary.as_deref(), Some("The user is Ada, a Rust developer."));
+        assert_eq!(conversation.messages.len(), 2);
+
+        let requests = server.requests();
+        assert!(requests[2].json()["messages"][1]["content"].as_str().unwrap().contains("my name is Ada"));
+        assert_eq!(requests[3].json()["model"], "gpt-4o-mini");
+        assert!(requests[3].json()["messages"][0]["content"].as_str().unwrap().contains("a Rust developer"));
+    }
+
+    #[test]
+    fn store_saves_lists_loads_and_deletes_conversations() {
+        use chat::{ChatModel, Message};
+        use conversation::Conversation;
+        use store::ConversationStore;
+
+        let dir = TempPath::new("openai-store");
+        let store = ConversationStore::new(&dir);
+        assert!(store.list().unwrap().is_empty());
+
+        let mut first = store.create(Conversation::new(ChatModel::Gpt4o, None));
+        first.conversation.push(Message::user("Plan a trip to Lisbon\nfor three days"));
+        first.conversation.push(Message::assistant("Day one: Alfama."));
+        store.save(&mut first).unwrap();
+        let mut second = store.create(Conversation::new(ChatModel::Gpt4o, None));
+        // created but never saved, e.g. because the first reply failed
+        store.create(Conversation::new(ChatModel::Gpt4o, None));
+        assert_eq!(store.list().unwrap().len(), 1);
+        store.save(&mut second).unwrap();
+
+        let loaded = store.load(&first.id).unwrap();
+        assert_eq!(loaded, first);
+        assert_eq!(loaded.title, "Plan a trip to Lisbon");
+
+        let listed = store.list().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed.iter().find(|info| info.id == first.id).unwrap().messages, 2);
+
+        store.delete(&second.id).unwrap();
+        assert_eq!(store.list().unwrap().len(), 1);
+        assert!(matches!(store.load("../secrets"), Err(OpenAiError::Storage(_))));
+    }
+
+    #[tokio::test]
+    async fn anthropic_provider_translates_requests_and_responses() {
+        use anthropic::AnthropicClient;
+        use chat::{FinishReason, Message};
+
+        let server = MockServer::start();
+        let provider = AnthropicClient::with_config(
+            AnthropicClient::config(&server.url(), "sk-ant-mock"),
+            chat::ChatModel::from("claude-3-5-haiku-latest"),
+        )
+        .unwrap();
+        server.enqueue(MockResponse::json(
+            200,
+            json!({
+                "id": "msg_1",
+                "type": "message",
+                "model": "claude-3-5-haiku-latest",
+                "content": [
+                    { "type": "text", "text": "Checking." },
+                    { "type": "tool_use", "id": "toolu_1", "name": "get_weather", "input": { "city": "Oslo" } }
+                ],
+                "stop_reason": "tool_use",
+                "usage": { "input_tokens": 12, "output_tokens": 7 }
+            }),
+        ));
+
+        let history = vec![Message::system("Be brief.")];
+        let reply = provider.prompt(String::from("Weather in Oslo?"), history).await.unwrap();
+
+        assert_eq!(reply.content, "Checking.");
+        let call = &reply.tool_calls.unwrap()[0];
+        assert_eq!(call.function.name, "get_weather");
+        assert_eq!(call.function.arguments, r#"{"city":"Oslo"}"#);
+
+        let request = &server.requests()[0];
+        assert_eq!(request.path, "/messages");
+        assert_eq!(request.header("x-api-key"), Some("sk-ant-mock"));
+        assert_eq!(request.header("anthropic-version"), Some(anthropic::API_VERSION));
+        let body = request.json();
+        assert_eq!(body["model"], "claude-3-5-haiku-latest");
+        assert_eq!(body["system"], "Be brief.");
+        assert_eq!(body["messages"], json!([{ "role": "user", "content": [{ "type": "text", "text": "Weather in Oslo?" }] }]));
+        assert_eq!(body["max_tokens"], 4096);
+
+        let sse = [
+            r#"{"type":"message_start","message":{"id":"msg_2"}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hel"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"lo"}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":2}}"#,
+            r#"{"type":"message_stop"}"#,
+        ]
+        .iter()
+        .map(|data| format!("event: x\ndata: {}\n\n", data))
+        .collect::<String>();
+        server.enqueue(MockResponse::new(200, "text/event-stream", sse.into_bytes()));
+
+        let mut stream = provider.prompt_stream(String::from("Hi"), vec![]).await.unwrap();
+        let mut tokens = vec![];
+        while let Some(token) = stream.next().await {
+            tokens.push(token.unwrap());
+        }
+        assert_eq!(tokens, vec!["Hel", "lo"]);
+        assert_eq!(stream.finish_reason(), Some(FinishReason::Stop));
+        assert_eq!(server.requests()[1].json()["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn providers_are_selected_by_name_and_use_their_default_model() {
+        use provider::{Backend, LocalProvider};
+
+        assert_eq!("ollama".parse::<Backend>(), Ok(Backend::Local));
+        assert_eq!("Anthropic".parse::<Backend>(), Ok(Backend::Anthropic));
+        assert!("gemini".parse::<Backend>().is_err());
+
+        let server = MockServer::start();
+        let local = LocalProvider::new(ClientConfig::local(&server.url()), chat::ChatModel::from("llama3.2")).unwrap();
+        server.enqueue(MockResponse::chat("Hi from llama"));
+
+        let provider: &dyn ChatProvider = &local;
+        let reply = provider.prompt(String::from("Hello"), vec![]).await.unwrap();
+
+        assert_eq!(reply.content, "Hi from llama");
+        assert_eq!(server.requests()[0].json()["model"], "llama3.2");
+        assert_eq!(server.requests()[0].header("authorization"), None);
+    }
+
+    #[test]
+    fn token_bucket_waits_for_refill() {
+        use bulk::TokenBucket;
+        use std::time::{Duration, Instant};
+
+        let start = Instant::now();
+        let mut bucket = TokenBucket::per_minute(60);
+
+        assert_eq!(bucket.take(50.0, start), None);
+        assert_eq!(bucket.take(20.0, start), Some(Duration::from_secs(10)));
+        assert_eq!(bucket.take(20.0, start + Duration::from_secs(10)), None);
+        // more than the whole bucket goes through once it has refilled completely
+        assert_eq!(bucket.take(500.0, start + Duration::from_secs(70)), None);
+        assert!(bucket.take(1.0, start + Duration::from_secs(70)).is_some());
+    }
+
+    #[tokio::test]
+    async fn prompt_many_keeps_order_and_reports_failures_per_item() {
+        use bulk::RateLimits;
+
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat("one"));
+        server.enqueue(MockResponse::error(400, "invalid_request_error", "bad", "Bad prompt"));
+        server.enqueue(MockResponse::chat("three"));
+
+        let limits = RateLimits {
+            max_concurrency: 1,
+            requests_per_minute: Some(600),
+            tokens_per_minute: Some(100_000),
+        };
+        let prompts = vec!["1".to_string(), "2".to_string(), "3".to_string()];
+        let results = bulk::prompt_many(&server.client(), prompts, &limits).await;
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().content, "one");
+        assert!(matches!(results[1], Err(OpenAiError::Api { status: 400, .. })));
+        assert_eq!(results[2].as_ref().unwrap().content, "three");
+    }
+
+    #[tokio::test]
+    async fn cached_provider_reuses_deterministic_completions() {
+        use cache::{is_cacheable, CachedProvider, DiskCache, MemoryCache};
+        use chat::{Message, Payload};
+
+        let payload = |temperature: f32| Payload {
+            messages: vec![Message::user("MARCO!")],
+            temperature: Some(temperature),
+            seed: Some(42),
+            ..Default::default()
+        };
+        // a seed at the default temperature, or temperature 0 without a seed, can still vary
+        assert!(!is_cacheable(&Payload { temperature: None, ..payload(0.0) }));
+        assert!(!is_cacheable(&Payload { seed: None, ..payload(0.0) }));
+
+        let server = MockServer::start();
+        let cached = CachedProvider::new(server.client(), MemoryCache::new(16));
+        server.enqueue(MockResponse::chat("POLO!"));
+        server.enqueue(MockResponse::chat("POLO again"));
+        server.enqueue(MockResponse::chat("warm"));
+        server.enqueue(MockResponse::chat("fresh"));
+
+        assert_eq!(cached.complete(payload(0.0)).await.unwrap().into_message().unwrap().content, "POLO!");
+        assert_eq!(cached.complete(payload(0.0)).await.unwrap().into_message().unwrap().content, "POLO!");
+        assert_eq!(server.requests().len(), 1);
+
+        // sampled answers are never cached
+        cached.complete(payload(0.7)).await.unwrap();
+        cached.complete(payload(0.7)).await.unwrap();
+        assert_eq!(server.requests().len(), 3);
+
+        cached.set_bypass(true);
+        assert_eq!(cached.complete(payload(0.0)).await.unwrap().into_message().unwrap().content, "fresh");
+        cached.set_bypass(false);
+        assert_eq!(cached.complete(payload(0.0)).await.unwrap().into_message().unwrap().content, "fresh");
+
+        // the disk cache is shared by separate instances, and a zero TTL expires immediately
+        let dir = TempPath::new("openai-cache");
+        server.enqueue(MockResponse::chat("from disk"));
+        server.enqueue(MockResponse::chat("expired"));
+        CachedProvider::new(server.client(), DiskCache::new(&dir)).complete(payload(0.0)).await.unwrap();
+        let reloaded = CachedProvider::new(server.client(), DiskCache::new(&dir));
+        assert_eq!(reloaded.complete(payload(0.0)).await.unwrap().into_message().unwrap().content, "from disk");
+        let expiring = reloaded.with_ttl(std::time::Duration::ZERO);
+        assert_eq!(expiring.complete(payload(0.0)).await.unwrap().into_message().unwrap().content, "expired");
+        assert_eq!(server.requests().len(), 6);
+    }
+
+    #[test]
+    fn payload_builder_validates_ranges_and_omits_unset_fields() {
+        use chat::{ChatModel, Payload};
+
+        let payload = Payload::builder()
+            .model(ChatModel::Gpt4o)
+            .system("Be brief.")
+            .user("MARCO!")
+            .temperature(0.2)
+            .max_tokens(150)
+            .build()
+            .unwrap();
+        assert_eq!(
+            json!(payload),
+            json!({
+                "model": "gpt-4o",
+                "messages": [
+                    { "role": "system", "content": "Be brief." },
+                    { "role": "user", "content": "MARCO!" }
+                ],
+                "temperature": 0.2f32,
+                "max_tokens": 150
+            })
+        );
+
+        let invalid = |builder: chat::PayloadBuilder| match builder.user("hi").build() {
+            Err(OpenAiError::InvalidRequest(msg)) => msg,
+            other => panic!("expected InvalidRequest, got {:?}", other),
+        };
+        assert_eq!(invalid(Payload::builder().temperature(2.5)), "temperature must be between 0 and 2, got 2.5");
+        assert!(invalid(Payload::builder().top_p(-0.1)).starts_with("top_p"));
+        assert!(invalid(Payload::builder().presence_penalty(f32::NAN)).starts_with("presence_penalty"));
+        assert_eq!(invalid(Payload::builder().top_logprobs(3)), "top_logprobs requires logprobs");
+        assert!(Payload::builder().build().is_err());
+        assert_eq!(
+            Payload::builder().user("hi").default_model(ChatModel::Gpt4oMini).build().unwrap().model(),
+            &ChatModel::Gpt4oMini
+        );
+    }
+
+    #[tokio::test]
+    async fn logprobs_are_requested_and_parsed() {
+        use chat::Payload;
+
+        let server = MockServer::start();
+        server.enqueue(MockResponse::json(
+            200,
+            json!({
+                "model": "gpt-4o-mini",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": "Yes" },
+                    "finish_reason": "stop",
+                    "logprobs": { "content": [{
+                        "token": "Yes",
+                        "logprob": -0.25,
+                        "bytes": [89, 101, 115],
+                        "top_logprobs": [
+                            { "token": "Yes", "logprob": -0.25, "bytes": [89, 101, 115] },
+                            { "token": "No", "logprob": -1.5, "bytes": [78, 111] }
+                        ]
+                    }], "refusal": null }
+                }]
+            }),
+        ));
+
+        let payload = Payload::builder()
+            .user("Is Rust memory safe? Answer Yes or No.")
+            .bias(9642, 10)
+            .bias(2360, 10)
+            .logprobs(true)
+            .top_logprobs(2)
+            .build()
+            .unwrap();
+        let completion = server.client().chat(payload).await.unwrap();
+
+        let body = server.requests()[0].json();
+        assert_eq!(body["logit_bias"], json!({ "9642": 10, "2360": 10 }));
+        assert_eq!(body["logprobs"], true);
+        assert_eq!(body["top_logprobs"], 2);
+
+        let logprobs = completion.choices[0].logprobs.as_ref().unwrap();
+        assert_eq!(logprobs.tokens()[0].bytes.as_deref(), Some("Yes".as_bytes()));
+        assert!((logprobs.confidence().unwrap() - (-0.25f64).exp()).abs() < 1e-9);
+        let labels = logprobs.label_probabilities(&["yes", "no", "maybe"]);
+        assert!((labels[1].1 - (-1.5f64).exp()).abs() < 1e-9);
+        assert_eq!(labels[2].1, 0.0);
+
+        assert!(Payload::builder().user("hi").bias(1, -101).build().is_err());
+        assert!(Payload::builder().user("hi").logprobs(false).top_logprobs(1).build().is_err());
+    }
+
+    #[test]
+    fn usage_tracker_prices_and_persists_totals() {
+        use usage::{Price, PriceTable, UsageTracker};
+
+        let mut prices = PriceTable::default();
+        prices.set("local-model", Price { prompt: 1.0, completion: 2.0 });
+        assert_eq!(prices.price("gpt-4o-mini-2024-07-18"), prices.price("gpt-4o-mini"));
+        assert_ne!(prices.price("gpt-4o-2024-08-06"), prices.price("gpt-4o-mini"));
+
+        let file = TempPath::new("openai-usage.jsonl");
+        let tracker = UsageTracker::with_file(prices.clone(), &file).unwrap();
+        // a second writer on the same file, like another process, adds to it rather than replacing it
+        let other = UsageTracker::with_file(prices.clone(), &file).unwrap();
+        tracker.record("local-model", 1_000_000, 500_000);
+        other.record("local-model", 0, 500_000);
+        tracker.record("unpriced", 10, 10);
+
+        let report = UsageTracker::with_file(prices, &file).unwrap().report();
+        let local = report.models["local-model"];
+        assert_eq!((local.requests, local.prompt_tokens, local.completion_tokens), (2, 1_000_000, 1_000_000));
+        assert!((local.cost - 3.0).abs() < 1e-9);
+        assert_eq!(report.models["unpriced"].cost, 0.0);
+        assert_eq!(report.total.requests, 3);
+
+        tracker.reset().unwrap();
+        assert!(tracker.report().models.is_empty());
+        assert!(UsageTracker::with_file(PriceTable::default(), &file).unwrap().report().models.is_empty());
+
+        // the totals object older versions wrote is converted to records
+        std::fs::write(&file, r#"{ "gpt-4o": { "requests": 2, "prompt_tokens": 10, "completion_tokens": 5, "cost": 0.5 } }"#).unwrap();
+        let migrated = UsageTracker::with_file(PriceTable::default(), &file).unwrap();
+        migrated.record("gpt-4o", 1, 1);
+        let gpt4o = UsageTracker::with_file(PriceTable::default(), &file).unwrap().report().models["gpt-4o"];
+        assert_eq!((gpt4o.requests, gpt4o.prompt_tokens, gpt4o.completion_tokens), (3, 11, 6));
+
+        std::fs::write(&file, "not usage\n").unwrap();
+        assert!(matches!(UsageTracker::with_file(PriceTable::default(), &file), Err(OpenAiError::Storage(_))));
+    }
+
+    #[tokio::test]
+    async fn chat_calls_report_usage_to_the_global_tracker() {
+        use chat::Payload;
+
+        let server = MockServer::start();
+        server.enqueue(MockResponse::json(
+            200,
+            json!({
+                "model": "usage-tracking-test",
+                "choices": [{ "index": 0, "message": { "role": "assistant", "content": "ok" }, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 7, "completion_tokens": 3, "total_tokens": 10 }
+            }),
+        ));
+        server.enqueue(MockResponse::chat_stream(&["a", "b"]));
+
+        server.client().chat(Payload::builder().user("hi").build().unwrap()).await.unwrap();
+        let tracked = usage::report().models["usage-tracking-test"];
+        assert_eq!((tracked.requests, tracked.prompt_tokens, tracked.completion_tokens), (1, 7, 3));
+
+        let mut stream = server.client().prompt_stream(String::from("hi"), vec![]).await.unwrap();
+        while stream.next().await.is_some() {}
+        assert_eq!(stream.usage().unwrap().completion_tokens, 2);
+        assert_eq!(server.requests()[1].json()["stream_options"]["include_usage"], true);
+    }
+
+    #[tokio::test]
+    async fn moderated_provider_refuses_flagged_input_and_output() {
+        use chat::Message;
+        use moderation::{ModeratedProvider, ModerationPolicy};
+
+        let moderation = |violence: f64| {
+            MockResponse::json(
+                200,
+                json!({
+                    "id": "modr-1",
+                    "model": "omni-moderation-latest",
+                    "results": [{
+                        "flagged": violence > 0.5,
+                        "categories": { "hate": false, "violence": violence > 0.5 },
+                        "category_scores": { "hate": 0.01, "violence": violence }
+                    }]
+                }),
+            )
+        };
+
+        let server = MockServer::start();
+        let boxed: Box<dyn ChatProvider> = Box::new(server.client());
+        let provider = ModeratedProvider::new(boxed, server.client(), ModerationPolicy::default());
+
+        server.enqueue_for("moderations", moderation(0.9));
+        let refused = provider.prompt(String::from("something violent"), vec![]).await;
+        match refused {
+            Err(OpenAiError::ContentFlagged { categories, output }) => {
+                assert_eq!(categories, vec!["violence"]);
+                assert!(!output);
+            }
+            other => panic!("expected ContentFlagged, got {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 1);
+
+        // the whole history is checked, system prompts and text parts included; a lower
+        // threshold catches milder output
+        let strict = ModerationPolicy::default().threshold("violence", 0.3).check_output(true);
+        let provider = ModeratedProvider::new(server.client(), server.client(), strict);
+        server.enqueue_for("moderations", moderation(0.1));
+        server.enqueue_for("chat/completions", MockResponse::chat("a mildly violent reply"));
+        server.enqueue_for("moderations", moderation(0.4));
+        let history = vec![
+            Message::system("Write in the voice of a pirate."),
+            Message::user(vec![content::ContentPart::text("earlier"), content::ContentPart::image_url("https://example.com/a.png")]),
+            Message::assistant("reply"),
+        ];
+        let refused = provider.prompt(String::from("tell me a story"), history).await;
+
+        assert!(matches!(refused, Err(OpenAiError::ContentFlagged { output: true, .. })));
+        assert_eq!(
+            server.requests()[1].json()["input"],
+            json!(["Write in the voice of a pirate.", "earlier", "tell me a story"])
+        );
+        assert_eq!(server.requests()[3].json()["input"], json!(["a mildly violent reply"]));
+
+        // a history ending in an assistant turn is still checked
+        server.enqueue_for("moderations", moderation(0.9));
+        let history = vec![Message::user("something violent"), Message::assistant("reply")];
+        let payload = chat::Payload::builder().messages(history).build().unwrap();
+        assert!(matches!(provider.complete(payload).await, Err(OpenAiError::ContentFlagged { output: false, .. })));
+        assert_eq!(server.requests().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn instruct_model_logprobs_are_returned_in_chat_shape() {
+        use chat::{ChatModel, Payload};
+
+        let server = MockServer::start();
+        server.enqueue(MockResponse::json(
+            200,
+            json!({
+                "model": "gpt-3.5-turbo-instruct",
+                "choices": [{
+                    "index": 0,
+                    "text": " Yes",
+                    "logprobs": {
+                        "tokens": [" Yes"],
+                        "token_logprobs": [-0.25],
+                        "top_logprobs": [{ " No": -1.5, " Yes": -0.25 }],
+                        "text_offset": [0]
+                    },
+                    "finish_reason": "stop"
+                }]
+            }),
+        ));
+
+        let payload = Payload::builder()
+            .model(ChatModel::Gpt3TurboInstruct)
+            .user("Is water wet?")
+            .logprobs(true)
+            .top_logprobs(2)
+            .build()
+            .unwrap();
+        let completion = server.client().chat(payload).await.unwrap();
+        assert_eq!(server.requests()[0].json()["logprobs"], 2);
+
+        let logprobs = completion.choices[0].logprobs.as_ref().unwrap();
+        let token = &logprobs.tokens()[0];
+        assert_eq!((token.token.as_str(), token.logprob), (" Yes", -0.25));
+        let alternatives: Vec<&str> = token.top_logprobs.iter().map(|top| top.token.as_str()).collect();
+        assert_eq!(alternatives, vec![" Yes", " No"]);
+    }
+
+    #[tokio::test]
+    async fn instruct_models_are_routed_to_the_completions_endpoint() {
+        use chat::{ChatModel, Message, Payload};
+        use completions::{CompletionRequest, Endpoint};
+
+        let server = MockServer::start();
+        let client = server.client();
+        server.enqueue(MockResponse::json(
+            200,
+            json!({
+                "id": "cmpl-1",
+                "model": "gpt-3.5-turbo-instruct",
+                "choices": [{ "index": 0, "text": " POLO!", "logprobs": null, "finish_reason": "stop" }],
+                "usage": { "prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5 }
+            }),
+        ));
+
+        let payload = Payload::builder()
+            .model(ChatModel::Gpt3TurboInstruct)
+            .system("Answer like a swimming pool game.")
+            .user("MARCO!")
+            .max_tokens(5)
+            .build()
+            .unwrap();
+        let reply = client.chat(payload).await.unwrap().into_message().unwrap();
+
+        assert_eq!(reply.content, " POLO!");
+        let request = &server.requests()[0];
+        assert_eq!(request.path, "/completions");
+        assert_eq!(request.json()["prompt"], "Answer like a swimming pool game.\n\nMARCO!");
+        assert_eq!(request.json()["max_tokens"], 5);
+
+        // chat models are refused by /v1/completions, and chat-only features by instruct models
+        let wrong = client.completions(&CompletionRequest::new(ChatModel::Gpt4o, "hi")).await;
+        assert!(matches!(wrong, Err(OpenAiError::WrongEndpoint { endpoint: "/v1/completions", .. })));
+        let tools = Payload::builder()
+            .model(ChatModel::Davinci002)
+            .user("hi")
+            .tools(vec![])
+            .build()
+            .unwrap();
+        assert!(matches!(client.chat(tools).await, Err(OpenAiError::InvalidRequest(_))));
+        // instruct-tuned chat models served locally are still chat models
+        assert_eq!(ChatModel::from("mistral:7b-instruct").endpoint(), Endpoint::Chat);
+        assert_eq!(ChatModel::from("llama3.1:8b-instruct-q4_0").endpoint(), Endpoint::Chat);
+        assert_eq!(ChatModel::from("ft:davinci-002:acme::abc123").endpoint(), Endpoint::Completions);
+
+        let sse = [" PO", "LO!"]
+            .iter()
+            .map(|text| format!("data: {}\n\n", json!({ "choices": [{ "index": 0, "text": text, "finish_reason": null }] })))
+            .collect::<String>()
+            + "data: [DONE]\n\n";
+        server.enqueue(MockResponse::new(200, "text/event-stream", sse.into_bytes()));
+        let history = vec![Message::user("MARCO!"), Message::assistant("POLO!")];
+        let payload = Payload::builder().model(ChatModel::Babbage002).messages(history).user("Again").build().unwrap();
+        let streamed = client.chat_stream(payload).await.unwrap().collect_message().await.unwrap();
+
+        assert_eq!(streamed.content, " POLO!");
+        assert_eq!(server.requests()[1].json()["prompt"], "user: MARCO!\n\nassistant: POLO!\n\nuser: Again\n\nassistant:");
+    }
+
+    #[test]
+    fn templates_load_from_a_directory_and_render_with_validation() {
+        use std::collections::HashMap;
+        use templates::TemplateLibrary;
+
+        let dir = TempPath::new("openai-templates");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("translate.toml"),
+            "description = \"Translate text\"\nsystem = \"You translate into {{ language }}.\"\nuser = \"{{input}}\"\nmodel = \"gpt-4o-mini\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("summarize.yaml"),
+            "name: summarize\nuser: \"TL;DR {{input}}\"\ndefaults:\n  unused: x\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a template").unwrap();
+
+        let mut library = TemplateLibrary::builtin();
+        library.load_dir(&dir).unwrap();
+        let names: Vec<&str> = library.list().iter().map(|template| template.name.as_str()).collect();
+        assert_eq!(names, vec!["explain-error", "summarize", "translate"]);
+
+        let translate = library.get("translate").unwrap();
+        assert_eq!(translate.required().into_iter().collect::<Vec<_>>(), vec!["input", "language"]);
+        let vars = HashMap::from([("input".to_string(), "Hello".to_string())]);
+        match translate.render(&vars) {
+            Err(OpenAiError::MissingVariables { template, missing }) => {
+                assert_eq!(template, "translate");
+                assert_eq!(missing, vec!["language"]);
+            }
+            other => panic!("expected MissingVariables, got {:?}", other),
+        }
+
+        let vars = HashMap::from([
+            ("input".to_string(), "Hello {{language}}".to_string()),
+            ("language".to_string(), "French".to_string()),
+        ]);
+        let payload = translate.payload(&vars).unwrap().build().unwrap();
+        assert_eq!(payload.model(), &chat::ChatModel::Gpt4oMini);
+        assert_eq!(payload.messages()[0].content, "You translate into French.");
+        // values are inserted as is, never expanded again
+        assert_eq!(payload.messages()[1].content, "Hello {{language}}");
+
+        let summary = library.get("summarize").unwrap().render(&vars).unwrap();
+        assert_eq!(summary[0].content, "TL;DR Hello {{language}}");
+        assert!(library.get("missing").is_err());
+    }
+
+    #[tokio::test]
+    async fn audio_is_transcribed_from_a_file_and_speech_streamed_to_one() {
+        use audio::{SpeechFormat, SpeechRequest, TranscriptionRequest, Voice};
+
+        let dir = TempPath::new("openai-audio");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("command.wav"), b"RIFF fake audio").unwrap();
+        let server = MockServer::start();
+        server.enqueue(MockResponse::json(
+            200,
+            json!({
+                "text": "list files",
+                "language": "english",
+                "duration": 1.2,
+                "words": [
+                    { "word": "list", "start": 0.1, "end": 0.5 },
+                    { "word": "files", "start": 0.6, "end": 1.1 }
+                ],
+                "segments": [{ "id": 0, "start": 0.0, "end": 1.2, "text": "list files", "no_speech_prob": 0.01 }]
+            }),
+        ));
+        server.enqueue(MockResponse::new(200, "audio/mpeg", vec![0xff, 0xf3, 0x00, 0x80]));
+        let client = server.client();
+
+        let mut request = TranscriptionRequest::new(dir.join("command.wav"));
+        request.language = Some("en".to_string());
+        let transcript = client.transcribe(&request.with_timestamps()).await.unwrap();
+        assert_eq!(transcript.text, "list files");
+        assert_eq!(transcript.words[1].word, "files");
+        assert_eq!(transcript.segments[0].end, 1.2);
+
+        let upload = &server.requests()[0];
+        assert_eq!(upload.path, "/audio/transcriptions");
+        assert!(upload.header("content-type").unwrap().starts_with("multipart/form-data"));
+        assert!(upload.body.contains("filename=\"command.wav\""));
+        assert!(upload.body.contains("RIFF fake audio"));
+        assert!(upload.body.contains("name=\"timestamp_granularities[]\"\r\n\r\nword"));
+
+        let mut speech = SpeechRequest::new("Here are your files", Voice::Nova);
+        speech.response_format = Some(SpeechFormat::Mp3);
+        let path = dir.join("reply").join(format!("answer.{}", SpeechFormat::Mp3.extension()));
+        assert_eq!(client.speech_to_file(&speech, &path).await.unwrap(), 4);
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0xff, 0xf3, 0x00, 0x80]);
+        assert_eq!(
+            server.requests()[1].json(),
+            json!({ "model": "tts-1", "input": "Here are your files", "voice": "nova", "response_format": "mp3" })
+        );
+
+        speech.speed = Some(8.0);
+        assert!(matches!(client.speech(&speech).await, Err(OpenAiError::InvalidRequest(_))));
+        let missing = TranscriptionRequest::new(dir.join("missing.wav"));
+        assert!(matches!(client.transcribe(&missing).await, Err(OpenAiError::Storage(_))));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn images_are_generated_edited_and_saved_to_disk() {
+        use base64::Engine;
+        use images::{ImageEditRequest, ImageFormat, ImageQuality, ImageRequest, ImageSize};
+
+        let png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a];
+        let encoded = base64::engine::general_purpose::STANDARD.encode(&png);
+        let dir = TempPath::new("openai-images");
+        let server = MockServer::start();
+        server.enqueue(MockResponse::json(
+            200,
+            json!({ "created": 1, "data": [{ "b64_json": encoded, "revised_prompt": "A glowing green cell" }] }),
+        ));
+        server.enqueue(MockResponse::json(
+            200,
+            json!({ "created": 2, "data": [{ "url": format!("{}/files/dead.png", server.url()) }] }),
+        ));
+        server.enqueue_for("/files/dead.png", MockResponse::new(200, "image/png", png.clone()));
+        let client = server.client();
+
+        let mut request = ImageRequest::new("A glowing green cell sprite");
+        request.quality = Some(ImageQuality::Hd);
+        let sprite = dir.join("sprites").join("alive_cell.png");
+        let image = client.generate_image_to_file(&request, &sprite).await.unwrap();
+        assert_eq!(image.revised_prompt.as_deref(), Some("A glowing green cell"));
+        assert_eq!(std::fs::read(&sprite).unwrap(), png);
+        assert_eq!(
+            server.requests()[0].json(),
+            json!({
+                "model": "dall-e-3",
+                "prompt": "A glowing green cell sprite",
+                "n": 1,
+                "quality": "hd",
+                "response_format": "b64_json"
+            })
+        );
+
+        let mut edit = ImageEditRequest::new(&sprite, "The same cell, but grey and dim");
+        edit.size = Some(ImageSize::Small);
+        edit.response_format = Some(ImageFormat::Url);
+        let edited = client.edit_images(&edit).await.unwrap();
+        client.save_image(&edited.data[0], dir.join("sprites/dead_cell.png")).await.unwrap();
+        assert_eq!(std::fs::read(dir.join("sprites/dead_cell.png")).unwrap(), png);
+
+        let upload = &server.requests()[1];
+        assert_eq!(upload.path, "/images/edits");
+        assert!(upload.body.contains("filename=\"alive_cell.png\""));
+        assert!(upload.body.contains("name=\"size\"\r\n\r\n256x256"));
+        assert!(upload.body.contains("dall-e-2"));
+
+        request.size = Some(ImageSize::Small);
+        assert!(matches!(client.generate_images(&request).await, Err(OpenAiError::InvalidRequest(_))));
+        edit.n = Some(11);
+        assert!(matches!(client.edit_images(&edit).await, Err(OpenAiError::InvalidRequest(_))));
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn batches_are_uploaded_polled_and_matched_back_to_their_ids() {
+        use batch::{BatchInput, BatchStatus, PollPolicy};
+        use chat::{ChatModel, Message, Payload};
+        use std::time::Duration;
+
+        let payload = |model: ChatModel, text: &str| Payload::builder().model(model).messages(vec![Message::user(text)]).build().unwrap();
+        let mut input = BatchInput::new();
+        for (id, text) in [("greet", "Say hi"), ("count", "Count to 3"), ("bad", "?"), ("late", "Write a novel")] {
+            input.push(id, payload(ChatModel::Gpt4oMini, text)).unwrap();
+        }
+        assert!(input.push("greet", payload(ChatModel::Gpt4oMini, "again")).is_err());
+        assert!(matches!(
+            input.push("instruct", payload(ChatModel::Gpt3TurboInstruct, "hi")),
+            Err(OpenAiError::WrongEndpoint { .. })
+        ));
+
+        let batch = |status: &str| {
+            MockResponse::json(
+                200,
+                json!({
+                    "id": "batch_1",
+                    "endpoint": "/v1/chat/completions",
+                    "status": status,
+                    "input_file_id": "file-in",
+                    "output_file_id": if status == "expired" { json!("file-out") } else { json!(null) },
+                    "error_file_id": if status == "expired" { json!("file-err") } else { json!(null) },
+                    "request_counts": { "total": 4, "completed": 2, "failed": 2 }
+                }),
+            )
+        };
+        let output_line = |id: &str, content: &str| {
+            let body = MockResponse::chat(content).body;
+            let mut body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            body["model"] = json!("batch-usage-test");
+            json!({ "id": "r", "custom_id": id, "response": { "status_code": 200, "body": body } }).to_string()
+        };
+        let errors = [
+            json!({
+                "custom_id": "bad",
+                "response": { "status_code": 400, "body": { "error": { "message": "Bad prompt", "type": "invalid_request_error" } } }
+            }),
+        ];
+
+        let server = MockServer::start();
+        server.enqueue_for("/files", MockResponse::json(200, json!({ "id": "file-in", "purpose": "batch" })));
+        server.enqueue_for("/batches", batch("validating"));
+        server.enqueue_for("/batches/batch_1", batch("in_progress"));
+        server.enqueue_for("/batches/batch_1", batch("expired"));
+        server.enqueue_for(
+            "/files/file-out/content",
+            MockResponse::new(200, "application/jsonl", format!("{}\n{}\n", output_line("count", "1 2 3"), output_line("greet", "Hi!")).into_bytes()),
+        );
+        server.enqueue_for("/files/file-err/content", MockResponse::new(200, "application/jsonl", format!("{}\n", errors[0]).into_bytes()));
+
+        let policy = PollPolicy {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let results = server.client().run_batch(&input, &policy).await.unwrap();
+
+        let ids: Vec<&str> = results.iter().map(|result| result.custom_id.as_str()).collect();
+        assert_eq!(ids, vec!["greet", "count", "bad", "late"]);
+        assert_eq!(results[0].result.as_ref().unwrap().choices[0].message.content, "Hi!");
+        assert_eq!(results[1].result.as_ref().unwrap().choices[0].message.content, "1 2 3");
+        assert!(matches!(&results[2].result, Err(OpenAiError::Api { status: 400, error }) if error.message == "Bad prompt"));
+        assert!(matches!(&results[3].result, Err(OpenAiError::BatchFailed { id, .. }) if id == "late"));
+
+        let requests = server.requests();
+        assert!(requests[0].body.contains("name=\"purpose\"\r\n\r\nbatch"));
+        let uploaded: Vec<&str> = requests[0].body.lines().filter(|line| line.starts_with('{')).collect();
+        assert_eq!(uploaded.len(), 4);
+        let first: serde_json::Value = serde_json::from_str(uploaded[0]).unwrap();
+        assert_eq!(first["custom_id"], "greet");
+        assert_eq!(first["url"], "/v1/chat/completions");
+        assert_eq!(first["body"]["messages"][0]["content"], "Say hi");
+        assert_eq!(
+            requests[1].json(),
+            json!({ "input_file_id": "file-in", "endpoint": "/v1/chat/completions", "completion_window": "24h" })
         );
+        assert_eq!(requests.len(), 6);
+        assert!(BatchStatus::Expired.is_done() && !BatchStatus::Finalizing.is_done());
+        assert_eq!(serde_json::from_value::<BatchStatus>(json!("paused")).unwrap(), BatchStatus::Unknown);
 
-        assert_eq!(res.is_ok(), true);
+        let tracked = usage::report().models["batch-usage-test"];
+        assert_eq!((tracked.requests, tracked.prompt_tokens, tracked.completion_tokens), (2, 2, 2));
     }
 }
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+
+// Per-token log probabilities returned on a choice when the payload asks for `logprobs`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ChoiceLogprobs {
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
+    // present instead of content when the model refused
+    #[serde(default)]
+    pub refusal: Option<Vec<TokenLogprob>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    // UTF-8 bytes of the token; a multi-byte character can be split across tokens
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+    // the most likely alternatives at this position, up to `top_logprobs` of them
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+}
+
+impl TokenLogprob {
+    pub fn probability(&self) -> f64 {
+        self.logprob.exp()
+    }
+}
+
+impl TopLogprob {
+    pub fn probability(&self) -> f64 {
+        self.logprob.exp()
+    }
+}
+
+impl ChoiceLogprobs {
+    pub fn tokens(&self) -> &[TokenLogprob] {
+        self.content.as_deref().unwrap_or_default()
+    }
+
+    // Log probability of the whole answer.
+    pub fn total_logprob(&self) -> f64 {
+        self.tokens().iter().map(|token| token.logprob).sum()
+    }
+
+    // Geometric mean of the token probabilities, a 0..1 confidence that doesn't shrink
+    // just because the answer is long. None without any tokens.
+    pub fn confidence(&self) -> Option<f64> {
+        let tokens = self.tokens();
+        if tokens.is_empty() {
+            return None;
+        }
+        Some((self.total_logprob() / tokens.len() as f64).exp())
+    }
+
+    // How likely each candidate was as the first token, for classifiers that ask for a
+    // one-word label. Candidates missing from top_logprobs get 0.
+    pub fn label_probabilities(&self, labels: &[&str]) -> Vec<(String, f64)> {
+        let first = self.tokens().first();
+        labels
+            .iter()
+            .map(|label| {
+                let probability = first
+                    .map(|token| {
+                        let alternatives = token.top_logprobs.iter().map(|top| (&top.token, top.logprob));
+                        std::iter::once((&token.token, token.logprob))
+                            .chain(alternatives)
+                            .find(|(candidate, _)| candidate.trim().eq_ignore_ascii_case(label))
+                            .map_or(0.0, |(_, logprob)| logprob.exp())
+                    })
+                    .unwrap_or(0.0);
+                (label.to_string(), probability)
+            })
+            .collect()
+    }
+}
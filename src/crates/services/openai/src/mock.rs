@@ -0,0 +1,606 @@
+// A local stand-in for the OpenAI API so the crate and its users can be tested offline.
+// Responses are either queued up front, replayed from a cassette file, or recorded from
+// a real upstream into a cassette for later replays.
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::net::TcpListener as StdTcpListener;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+use crate::client::{ClientConfig, OpenAiClient};
+use crate::retry::RetryPolicy;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> MockResponse {
+        MockResponse {
+            status,
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            body,
+        }
+    }
+
+    pub fn json(status: u16, body: Value) -> MockResponse {
+        MockResponse::new(status, "application/json", body.to_string().into_bytes())
+    }
+
+    // A chat completion whose single choice is an assistant message with this content.
+    pub fn chat(content: &str) -> MockResponse {
+        MockResponse::json(
+            200,
+            json!({
+                "id": "chatcmpl-mock",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-3.5-turbo",
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": content },
+                    "finish_reason": "stop"
+                }],
+                "usage": { "prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2 }
+            }),
+        )
+    }
+
+    // A streamed chat completion sending each token as its own chunk.
+    pub fn chat_stream(tokens: &[&str]) -> MockResponse {
+        let chunk = |delta: Value, finish_reason: Value| {
+            let data = json!({
+                "id": "chatcmpl-mock",
+                "object": "chat.completion.chunk",
+                "model": "gpt-3.5-turbo",
+                "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }]
+            });
+            format!("data: {}\n\n", data)
+        };
+
+        let mut body = chunk(json!({ "role": "assistant", "content": "" }), Value::Null);
+        for token in tokens {
+            body.push_str(&chunk(json!({ "content": token }), Value::Null));
+        }
+        body.push_str(&chunk(json!({}), json!("stop")));
+        // what the API sends last when stream_options.include_usage is set
+        let usage = json!({
+            "id": "chatcmpl-mock",
+            "object": "chat.completion.chunk",
+            "model": "gpt-3.5-turbo",
+            "choices": [],
+            "usage": { "prompt_tokens": 1, "completion_tokens": tokens.len(), "total_tokens": 1 + tokens.len() }
+        });
+        body.push_str(&format!("data: {}\n\n", usage));
+        body.push_str("data: [DONE]\n\n");
+
+        MockResponse::new(200, "text/event-stream", body.into_bytes())
+    }
+
+    // An API error body in the shape OpenAI returns.
+    pub fn error(status: u16, kind: &str, code: &str, message: &str) -> MockResponse {
+        MockResponse::json(
+            status,
+            json!({ "error": { "message": message, "type": kind, "param": null, "code": code } }),
+        )
+    }
+
+    pub fn rate_limited(retry_after_secs: u64) -> MockResponse {
+        MockResponse::error(429, "requests", "rate_limit_exceeded", "Rate limit reached")
+            .with_header("Retry-After", &retry_after_secs.to_string())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub query: String,
+    #[serde(default, skip_serializing)]
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    // set when the body isn't UTF-8 (e.g. audio) and is stored base64-encoded
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub base64: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Cassette> {
+        let data = fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if let Some(parent) = path.as_ref().parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data)
+    }
+}
+
+impl From<&RecordedResponse> for MockResponse {
+    fn from(recorded: &RecordedResponse) -> Self {
+        use base64::Engine;
+
+        let body = if recorded.base64 {
+            base64::engine::general_purpose::STANDARD
+                .decode(&recorded.body)
+                .unwrap_or_default()
+        } else {
+            recorded.body.clone().into_bytes()
+        };
+        MockResponse {
+            status: recorded.status,
+            headers: recorded.headers.clone(),
+            body,
+        }
+    }
+}
+
+impl From<&MockResponse> for RecordedResponse {
+    fn from(response: &MockResponse) -> Self {
+        use base64::Engine;
+
+        let (body, base64) = match String::from_utf8(response.body.clone()) {
+            Ok(text) => (text, false),
+            Err(_) => (base64::engine::general_purpose::STANDARD.encode(&response.body), true),
+        };
+        RecordedResponse {
+            status: response.status,
+            headers: response.headers.clone(),
+            body,
+            base64,
+        }
+    }
+}
+
+enum Mode {
+    Queue,
+    Replay { cassette: Cassette, used: Vec<bool> },
+    Record { upstream: Box<OpenAiClient>, cassette: Cassette, path: PathBuf },
+}
+
+struct State {
+    queue: VecDeque<(Option<String>, MockResponse)>,
+    requests: Vec<RecordedRequest>,
+    mode: Mode,
+}
+
+pub struct MockServer {
+    url: String,
+    state: Arc<Mutex<State>>,
+    shutdown: Option<oneshot::Sender<()>>,
+}
+
+impl MockServer {
+    // Serves queued responses. The server runs on its own thread and runtime, so it
+    // works from sync tests, tokio tests and actix tests alike.
+    pub fn start() -> MockServer {
+        MockServer::with_mode(Mode::Queue)
+    }
+
+    // Answers each request with the matching interaction from the cassette.
+    pub fn replay(cassette: Cassette) -> MockServer {
+        let used = vec![false; cassette.interactions.len()];
+        MockServer::with_mode(Mode::Replay { cassette, used })
+    }
+
+    pub fn replay_file<P: AsRef<Path>>(path: P) -> io::Result<MockServer> {
+        Ok(MockServer::replay(Cassette::load(path)?))
+    }
+
+    // Forwards every request to the real API and appends the exchange to the cassette
+    // file, which is rewritten after each interaction.
+    pub fn record<P: AsRef<Path>>(upstream: ClientConfig, path: P) -> io::Result<MockServer> {
+        let upstream = OpenAiClient::new(upstream).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(MockServer::with_mode(Mode::Record {
+            upstream: Box::new(upstream),
+            cassette: Cassette::default(),
+            path: path.as_ref().to_path_buf(),
+        }))
+    }
+
+    fn with_mode(mode: Mode) -> MockServer {
+        let listener = StdTcpListener::bind("127.0.0.1:0").expect("failed to bind mock server");
+        listener.set_nonblocking(true).expect("failed to configure mock server");
+        let url = format!("http://{}", listener.local_addr().expect("mock server has no address"));
+
+        let state = Arc::new(Mutex::new(State {
+            queue: VecDeque::new(),
+            requests: vec![],
+            mode,
+        }));
+        let (shutdown, mut stop) = oneshot::channel();
+
+        let server_state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to start mock server runtime");
+
+            rt.block_on(async move {
+                let listener = TcpListener::from_std(listener).expect("failed to start mock server");
+                loop {
+                    tokio::select! {
+                        _ = &mut stop => break,
+                        accepted = listener.accept() => {
+                            if let Ok((socket, _)) = accepted {
+                                tokio::spawn(handle_connection(socket, Arc::clone(&server_state)));
+                            }
+                        }
+                    }
+                }
+            });
+        });
+
+        MockServer {
+            url,
+            state,
+            shutdown: Some(shutdown),
+        }
+    }
+
+    // Use as the client's base_url; request paths are relative to it (e.g. /chat/completions).
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    // A client pointed at this server, with retries that don't wait.
+    pub fn client(&self) -> OpenAiClient {
+        OpenAiClient::new(self.config()).expect("failed to build mock client")
+    }
+
+    pub fn config(&self) -> ClientConfig {
+        ClientConfig {
+            base_url: self.url(),
+            api_key: Some("sk-mock".to_string()),
+            // behaves like api.openai.com
+            stream_usage: true,
+            retry: RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // Queues a response for the next request to any path.
+    pub fn enqueue(&self, response: MockResponse) {
+        self.state.lock().unwrap().queue.push_back((None, response));
+    }
+
+    // Queues a response for the next request to this path.
+    pub fn enqueue_for(&self, path: &str, response: MockResponse) {
+        let path = format!("/{}", path.trim_start_matches('/'));
+        self.state.lock().unwrap().queue.push_back((Some(path), response));
+    }
+
+    // Every request received so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+    }
+}
+
+// A path under the system temp directory that no other test uses, removed with everything
+// written there when dropped, so tests clean up after themselves even when an assert fails.
+// `new("cassette.json")` gives e.g. `cassette-<pid>-<n>.json`.
+#[derive(Debug)]
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(name: &str) -> TempPath {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let unique = format!("{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let name = match name.split_once('.') {
+            Some((stem, extension)) => format!("{}-{}.{}", stem, unique, extension),
+            None => format!("{}-{}", name, unique),
+        };
+        TempPath(std::env::temp_dir().join(name))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<&TempPath> for PathBuf {
+    fn from(path: &TempPath) -> PathBuf {
+        path.0.clone()
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = if self.0.is_dir() {
+            fs::remove_dir_all(&self.0)
+        } else {
+            fs::remove_file(&self.0)
+        };
+    }
+}
+
+async fn handle_connection(mut socket: TcpStream, state: Arc<Mutex<State>>) {
+    let Ok(Some(request)) = read_request(&mut socket).await else {
+        return;
+    };
+    state.lock().unwrap().requests.push(request.clone());
+
+    let response = respond(&state, &request).await;
+    let _ = write_response(&mut socket, &response).await;
+}
+
+async fn respond(state: &Arc<Mutex<State>>, request: &RecordedRequest) -> MockResponse {
+    let upstream = {
+        let mut state = state.lock().unwrap();
+        let state = &mut *state;
+        match &mut state.mode {
+            Mode::Queue => {
+                let position = state
+                    .queue
+                    .iter()
+                    .position(|(path, _)| match path {
+                        Some(path) => *path == request.path,
+                        None => true,
+                    });
+                return match position.and_then(|i| state.queue.remove(i)) {
+                    Some((_, response)) => response,
+                    None => unmatched(request),
+                };
+            }
+            Mode::Replay { cassette, used } => {
+                return match find_interaction(cassette, used, request) {
+                    Some(i) => {
+                        used[i] = true;
+                        MockResponse::from(&cassette.interactions[i].response)
+                    }
+                    None => unmatched(request),
+                };
+            }
+            Mode::Record { upstream, .. } => (**upstream).clone(),
+        }
+    };
+
+    let response = match forward(&upstream, request).await {
+        Ok(response) => response,
+        Err(e) => MockResponse::error(502, "mock_error", "upstream_failed", &e.to_string()),
+    };
+
+    let mut state = state.lock().unwrap();
+    if let Mode::Record { cassette, path, .. } = &mut state.mode {
+        cassette.interactions.push(Interaction {
+            request: request.clone(),
+            response: RecordedResponse::from(&response),
+        });
+        let _ = cassette.save(&*path);
+    }
+    response
+}
+
+// Prefers an unused interaction with the same method, path and body (compared as JSON
+// when possible), then falls back to the next unused one for the same method and path.
+fn find_interaction(cassette: &Cassette, used: &[bool], request: &RecordedRequest) -> Option<usize> {
+    let same_route = |i: &usize| {
+        let recorded = &cassette.interactions[*i].request;
+        !used[*i] && recorded.method == request.method && recorded.path == request.path
+    };
+    let same_body = |i: &usize| {
+        let recorded = &cassette.interactions[*i].request;
+        match (recorded.json(), request.json()) {
+            (Value::Null, _) | (_, Value::Null) => recorded.body == request.body,
+            (a, b) => a == b,
+        }
+    };
+
+    let candidates: Vec<usize> = (0..cassette.interactions.len()).filter(same_route).collect();
+    candidates
+        .iter()
+        .copied()
+        .find(same_body)
+        .or_else(|| candidates.first().copied())
+}
+
+fn unmatched(request: &RecordedRequest) -> MockResponse {
+    MockResponse::error(
+        500,
+        "mock_error",
+        "no_mock_response",
+        &format!("No mock response for {} {}", request.method, request.path),
+    )
+}
+
+async fn forward(upstream: &OpenAiClient, request: &RecordedRequest) -> Result<MockResponse, reqwest::Error> {
+    let method = reqwest::Method::from_bytes(request.method.as_bytes()).unwrap_or(reqwest::Method::GET);
+    let path = if request.query.is_empty() {
+        request.path.clone()
+    } else {
+        format!("{}?{}", request.path, request.query)
+    };
+
+    let mut builder = upstream.request(method, &path).body(request.body.clone());
+    if let Some(content_type) = request.header("content-type") {
+        builder = builder.header("Content-Type", content_type);
+    }
+
+    let response = builder.send().await?;
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            name == "content-type" || name.starts_with("x-ratelimit") || name.starts_with("retry-after")
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let body = response.bytes().await?.to_vec();
+
+    Ok(MockResponse { status, headers, body })
+}
+
+async fn read_request(socket: &mut TcpStream) -> io::Result<Option<RecordedRequest>> {
+    let mut data = vec![];
+    let mut buf = [0; 8192];
+
+    let header_end = loop {
+        if let Some(end) = find(&data, b"\r\n\r\n") {
+            break end;
+        }
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let target = request_line.next().unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.to_lowercase())
+    };
+    let mut body = data[header_end + 4..].to_vec();
+
+    if header("transfer-encoding").is_some_and(|v| v.contains("chunked")) {
+        while find(&body, b"0\r\n\r\n").is_none() {
+            let n = socket.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+        body = decode_chunked(&body);
+    } else {
+        let length = header("content-length").and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
+        while body.len() < length {
+            let n = socket.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    Ok(Some(RecordedRequest {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: String::from_utf8_lossy(&body).to_string(),
+    }))
+}
+
+fn decode_chunked(mut data: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    while let Some(line_end) = find(data, b"\r\n") {
+        let size = String::from_utf8_lossy(&data[..line_end]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("0").trim(), 16).unwrap_or(0);
+        if size == 0 || data.len() < line_end + 2 + size {
+            break;
+        }
+        body.extend_from_slice(&data[line_end + 2..line_end + 2 + size]);
+        data = &data[(line_end + 4 + size).min(data.len())..];
+    }
+    body
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+async fn write_response(socket: &mut TcpStream, response: &MockResponse) -> io::Result<()> {
+    let reason = reqwest::StatusCode::from_u16(response.status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or("Unknown");
+
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()));
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(&response.body).await?;
+    socket.shutdown().await
+}
@@ -0,0 +1,50 @@
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+
+use crate::chat::ChatModel;
+use crate::client::{default_client, OpenAiClient};
+use crate::error::OpenAiError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub id: String,
+    #[serde(default)]
+    pub object: String,
+    #[serde(default)]
+    pub created: u64,
+    #[serde(default)]
+    pub owned_by: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+impl ModelInfo {
+    pub fn model(&self) -> ChatModel {
+        ChatModel::from(self.id.as_str())
+    }
+}
+
+impl OpenAiClient {
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, OpenAiError> {
+        let body = self.send(self.request(Method::GET, "models")).await?.text().await?;
+
+        serde_json::from_str::<ModelList>(&body)
+            .map(|list| list.data)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing model list: {}", e)))
+    }
+
+    pub async fn retrieve_model(&self, id: &str) -> Result<ModelInfo, OpenAiError> {
+        let request = self.request(Method::GET, &format!("models/{}", id));
+        let body = self.send(request).await?.text().await?;
+
+        serde_json::from_str::<ModelInfo>(&body)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing model: {}", e)))
+    }
+}
+
+pub async fn list_models() -> Result<Vec<ModelInfo>, OpenAiError> {
+    default_client()?.list_models().await
+}
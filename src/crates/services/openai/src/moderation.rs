@@ -0,0 +1,232 @@
+use async_trait::async_trait;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+
+use crate::chat::{ChatCompletion, ChatModel, ChatStream, Payload, Role};
+use crate::client::{default_client, OpenAiClient};
+use crate::content::{Content, ContentPart};
+use crate::error::OpenAiError;
+use crate::provider::ChatProvider;
+
+pub const DEFAULT_MODEL: &str = "omni-moderation-latest";
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ModerationRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+impl ModerationRequest {
+    pub fn new<S: Into<String>>(input: Vec<S>) -> ModerationRequest {
+        ModerationRequest {
+            model: DEFAULT_MODEL.to_string(),
+            input: input.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ModerationResponse {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub model: String,
+    // one per input, in the same order
+    pub results: Vec<ModerationResult>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ModerationResult {
+    pub flagged: bool,
+    // category names like "hate", "violence" or "self-harm/intent"
+    pub categories: BTreeMap<String, bool>,
+    // 0..1 confidence per category
+    pub category_scores: BTreeMap<String, f64>,
+}
+
+impl OpenAiClient {
+    pub async fn moderate(&self, request: &ModerationRequest) -> Result<ModerationResponse, OpenAiError> {
+        let http_request = self.request(Method::POST, "moderations").json(request);
+        let body = self.send(http_request).await?.text().await?;
+
+        serde_json::from_str::<ModerationResponse>(&body)
+            .map_err(|e| OpenAiError::MalformedResponse(format!("Error parsing moderation: {}", e)))
+    }
+}
+
+pub async fn moderate(request: &ModerationRequest) -> Result<ModerationResponse, OpenAiError> {
+    default_client()?.moderate(request).await
+}
+
+// Decides which results count as violations. Without thresholds the API's own `flagged`
+// verdict is used; a threshold makes a category trip once its score reaches it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ModerationPolicy {
+    pub default_threshold: Option<f64>,
+    pub thresholds: HashMap<String, f64>,
+    // check the model's reply too, not just what was sent
+    pub check_output: bool,
+}
+
+impl ModerationPolicy {
+    pub fn threshold(mut self, category: &str, threshold: f64) -> ModerationPolicy {
+        self.thresholds.insert(category.to_string(), threshold);
+        self
+    }
+
+    pub fn check_output(mut self, check_output: bool) -> ModerationPolicy {
+        self.check_output = check_output;
+        self
+    }
+
+    // LLM_MODERATION is off (the default), input or all; LLM_MODERATION_THRESHOLD sets a
+    // score threshold for every category.
+    pub fn from_env() -> Result<Option<ModerationPolicy>, OpenAiError> {
+        let check_output = match env::var("LLM_MODERATION").as_deref() {
+            Err(_) | Ok("") | Ok("off") => return Ok(None),
+            Ok("input") => false,
+            Ok("all") => true,
+            Ok(other) => {
+                return Err(OpenAiError::Config(format!(
+                    "LLM_MODERATION must be off, input or all, got {:?}",
+                    other
+                )))
+            }
+        };
+        let default_threshold = match env::var("LLM_MODERATION_THRESHOLD") {
+            Ok(value) => Some(value.parse::<f64>().map_err(|_| {
+                OpenAiError::Config(format!("LLM_MODERATION_THRESHOLD must be a number, got {:?}", value))
+            })?),
+            Err(_) => None,
+        };
+
+        Ok(Some(ModerationPolicy {
+            default_threshold,
+            check_output,
+            ..Default::default()
+        }))
+    }
+
+    // The categories this result violates, sorted by name.
+    pub fn violations(&self, result: &ModerationResult) -> Vec<String> {
+        if self.default_threshold.is_none() && self.thresholds.is_empty() {
+            return result
+                .categories
+                .iter()
+                .filter(|(_, flagged)| **flagged)
+                .map(|(category, _)| category.clone())
+                .collect();
+        }
+
+        result
+            .category_scores
+            .iter()
+            .filter(|(category, score)| {
+                match self.thresholds.get(category.as_str()).or(self.default_threshold.as_ref()) {
+                    Some(threshold) => **score >= *threshold,
+                    None => result.categories.get(category.as_str()).copied().unwrap_or(false),
+                }
+            })
+            .map(|(category, _)| category.clone())
+            .collect()
+    }
+}
+
+// Wraps a provider so user input, and optionally the reply, passes the moderation endpoint
+// first. Moderation always goes to OpenAI, whichever provider answers.
+pub struct ModeratedProvider<P> {
+    inner: P,
+    moderator: OpenAiClient,
+    policy: ModerationPolicy,
+}
+
+impl<P: ChatProvider> ModeratedProvider<P> {
+    pub fn new(inner: P, moderator: OpenAiClient, policy: ModerationPolicy) -> ModeratedProvider<P> {
+        ModeratedProvider { inner, moderator, policy }
+    }
+
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    async fn check(&self, texts: Vec<String>, output: bool) -> Result<(), OpenAiError> {
+        let texts: Vec<String> = texts.into_iter().filter(|text| !text.trim().is_empty()).collect();
+        if texts.is_empty() {
+            return Ok(());
+        }
+
+        let response = self.moderator.moderate(&ModerationRequest::new(texts)).await?;
+        let mut categories: Vec<String> = response
+            .results
+            .iter()
+            .flat_map(|result| self.policy.violations(result))
+            .collect();
+        categories.sort();
+        categories.dedup();
+
+        if categories.is_empty() {
+            Ok(())
+        } else {
+            Err(OpenAiError::ContentFlagged { categories, output })
+        }
+    }
+
+    // Every user and system text, not just the latest turn: callers can pass in any history,
+    // and templates put user-supplied values into system prompts.
+    async fn check_input(&self, payload: &Payload) -> Result<(), OpenAiError> {
+        let texts = payload
+            .messages
+            .iter()
+            .filter(|message| matches!(message.role, Role::User | Role::System))
+            .flat_map(|message| match &message.content {
+                Content::Text(text) => vec![text.clone()],
+                Content::Parts(parts) => parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ContentPart::Text { text } => Some(text.clone()),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect(),
+            })
+            .collect();
+        self.check(texts, false).await
+    }
+}
+
+#[async_trait]
+impl<P: ChatProvider> ChatProvider for ModeratedProvider<P> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn default_model(&self) -> ChatModel {
+        self.inner.default_model()
+    }
+
+    async fn complete(&self, payload: Payload) -> Result<ChatCompletion, OpenAiError> {
+        self.check_input(&payload).await?;
+        let completion = self.inner.complete(payload).await?;
+
+        if self.policy.check_output {
+            let replies = completion
+                .choices
+                .iter()
+                .map(|choice| choice.message.content.text())
+                .collect();
+            self.check(replies, true).await?;
+        }
+        Ok(completion)
+    }
+
+    // Streamed replies reach the caller as they are generated, so only the input is checked.
+    async fn stream(&self, payload: Payload) -> Result<ChatStream, OpenAiError> {
+        self.check_input(&payload).await?;
+        self.inner.stream(payload).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, OpenAiError> {
+        self.inner.list_models().await
+    }
+}
@@ -0,0 +1,255 @@
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use crate::anthropic::AnthropicClient;
+use crate::chat::{ChatCompletion, ChatModel, ChatStream, Message, Payload, PayloadBuilder};
+use crate::client::{ClientConfig, OpenAiClient};
+use crate::error::OpenAiError;
+use crate::moderation::{ModeratedProvider, ModerationPolicy};
+
+pub const DEFAULT_LOCAL_URL: &str = "http://localhost:11434/v1";
+pub const DEFAULT_LOCAL_MODEL: &str = "llama3.2";
+
+// A chat backend. OpenAI's request and response types are the common language; each
+// provider translates to and from its own API.
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    fn name(&self) -> &str;
+
+    // The model used by prompt and prompt_stream.
+    fn default_model(&self) -> ChatModel;
+
+    async fn complete(&self, payload: Payload) -> Result<ChatCompletion, OpenAiError>;
+
+    async fn stream(&self, payload: Payload) -> Result<ChatStream, OpenAiError>;
+
+    async fn list_models(&self) -> Result<Vec<String>, OpenAiError>;
+
+    async fn prompt(&self, text: String, mut conversation: Vec<Message>) -> Result<Message, OpenAiError> {
+        conversation.push(Message::user(text));
+        let payload = Payload {
+            model: self.default_model(),
+            messages: conversation,
+            ..Default::default()
+        };
+        self.complete(payload).await?.into_message()
+    }
+
+    async fn prompt_stream(&self, text: String, mut conversation: Vec<Message>) -> Result<ChatStream, OpenAiError> {
+        conversation.push(Message::user(text));
+        let payload = Payload {
+            model: self.default_model(),
+            messages: conversation,
+            ..Default::default()
+        };
+        self.stream(payload).await
+    }
+}
+
+#[async_trait]
+impl ChatProvider for OpenAiClient {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    fn default_model(&self) -> ChatModel {
+        ChatModel::default()
+    }
+
+    async fn complete(&self, payload: Payload) -> Result<ChatCompletion, OpenAiError> {
+        self.chat(payload).await
+    }
+
+    async fn stream(&self, payload: Payload) -> Result<ChatStream, OpenAiError> {
+        self.chat_stream(payload).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, OpenAiError> {
+        let models = OpenAiClient::list_models(self).await?;
+        Ok(models.into_iter().map(|model| model.id).collect())
+    }
+}
+
+// Lets boxed providers be wrapped, e.g. in a CachedProvider, like concrete ones.
+#[async_trait]
+impl<P: ChatProvider + ?Sized> ChatProvider for Box<P> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn default_model(&self) -> ChatModel {
+        (**self).default_model()
+    }
+
+    async fn complete(&self, payload: Payload) -> Result<ChatCompletion, OpenAiError> {
+        (**self).complete(payload).await
+    }
+
+    async fn stream(&self, payload: Payload) -> Result<ChatStream, OpenAiError> {
+        (**self).stream(payload).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, OpenAiError> {
+        (**self).list_models().await
+    }
+}
+
+// An OpenAI-compatible server on this machine, such as Ollama, llama.cpp or vLLM.
+#[derive(Debug, Clone)]
+pub struct LocalProvider {
+    client: OpenAiClient,
+    model: ChatModel,
+}
+
+impl LocalProvider {
+    pub fn new(config: ClientConfig, model: ChatModel) -> Result<LocalProvider, OpenAiError> {
+        Ok(LocalProvider {
+            client: OpenAiClient::new(config)?,
+            model,
+        })
+    }
+
+    // Reads LOCAL_LLM_BASE_URL and LOCAL_LLM_MODEL, defaulting to Ollama's endpoint.
+    pub fn from_env() -> Result<LocalProvider, OpenAiError> {
+        let base_url = env::var("LOCAL_LLM_BASE_URL").unwrap_or_else(|_| DEFAULT_LOCAL_URL.to_string());
+        let model = env::var("LOCAL_LLM_MODEL").unwrap_or_else(|_| DEFAULT_LOCAL_MODEL.to_string());
+        LocalProvider::new(ClientConfig::local(&base_url), ChatModel::from(model.as_str()))
+    }
+
+    pub fn client(&self) -> &OpenAiClient {
+        &self.client
+    }
+}
+
+#[async_trait]
+impl ChatProvider for LocalProvider {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn default_model(&self) -> ChatModel {
+        self.model.clone()
+    }
+
+    async fn complete(&self, payload: Payload) -> Result<ChatCompletion, OpenAiError> {
+        self.client.chat(payload).await
+    }
+
+    async fn stream(&self, payload: Payload) -> Result<ChatStream, OpenAiError> {
+        self.client.chat_stream(payload).await
+    }
+
+    async fn list_models(&self) -> Result<Vec<String>, OpenAiError> {
+        ChatProvider::list_models(&self.client).await
+    }
+}
+
+// Which backend to talk to; parsed from names like "openai", "local" or "anthropic".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    OpenAi,
+    Local,
+    Anthropic,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "openai" => Ok(Backend::OpenAi),
+            "local" | "ollama" => Ok(Backend::Local),
+            "anthropic" | "claude" => Ok(Backend::Anthropic),
+            other => Err(format!("unknown LLM provider {:?}, expected openai, local or anthropic", other)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::OpenAi => write!(f, "openai"),
+            Backend::Local => write!(f, "local"),
+            Backend::Anthropic => write!(f, "anthropic"),
+        }
+    }
+}
+
+impl Backend {
+    // The backend named by LLM_PROVIDER, OpenAI when unset.
+    pub fn from_env() -> Result<Backend, OpenAiError> {
+        match env::var("LLM_PROVIDER") {
+            Ok(name) => name.parse().map_err(OpenAiError::Config),
+            Err(_) => Ok(Backend::default()),
+        }
+    }
+
+    // Builds the provider from its environment variables, behind the moderation filter when
+    // LLM_MODERATION is set.
+    pub fn provider(self) -> Result<Box<dyn ChatProvider>, OpenAiError> {
+        let provider: Box<dyn ChatProvider> = match self {
+            Backend::OpenAi => Box::new(OpenAiClient::from_env()?),
+            Backend::Local => Box::new(LocalProvider::from_env()?),
+            Backend::Anthropic => Box::new(AnthropicClient::from_env()?),
+        };
+        Ok(match ModerationPolicy::from_env()? {
+            Some(policy) => Box::new(ModeratedProvider::new(provider, OpenAiClient::from_env()?, policy)),
+            None => provider,
+        })
+    }
+}
+
+// The provider selected by LLM_PROVIDER.
+pub fn from_env() -> Result<Box<dyn ChatProvider>, OpenAiError> {
+    Backend::from_env()?.provider()
+}
+
+static DEFAULT_PROVIDER: OnceLock<Box<dyn ChatProvider>> = OnceLock::new();
+
+// The LLM_PROVIDER-selected provider, built once and shared like default_client.
+pub fn default_provider() -> Result<&'static dyn ChatProvider, OpenAiError> {
+    if let Some(provider) = DEFAULT_PROVIDER.get() {
+        return Ok(provider.as_ref());
+    }
+    let provider = from_env()?;
+    Ok(DEFAULT_PROVIDER.get_or_init(|| provider).as_ref())
+}
+
+// Blocking streaming prompt against any backend, for callers without a runtime.
+pub fn prompt_stream_sync<F>(
+    backend: Backend,
+    text: String,
+    mut conversation: Vec<Message>,
+    on_token: F,
+) -> Result<Message, OpenAiError>
+where
+    F: FnMut(&str),
+{
+    conversation.push(Message::user(text));
+    chat_stream_sync(backend, Payload::builder().messages(conversation), on_token)
+}
+
+// Blocking streaming chat with full control of the payload; the provider's default model is
+// used unless the builder names one. The provider is built here so its connection pool lives
+// and dies with this runtime.
+pub fn chat_stream_sync<F>(backend: Backend, payload: PayloadBuilder, mut on_token: F) -> Result<Message, OpenAiError>
+where
+    F: FnMut(&str),
+{
+    let provider = backend.provider()?;
+    let payload = payload.default_model(provider.default_model()).build()?;
+    let rt = tokio::runtime::Runtime::new().map_err(OpenAiError::Runtime)?;
+
+    rt.block_on(async {
+        let mut stream = provider.stream(payload).await?;
+        while let Some(token) = stream.next().await {
+            on_token(&token?);
+        }
+        Ok(stream.message())
+    })
+}
@@ -0,0 +1,134 @@
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // total attempts including the first one; 1 disables retries
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+    // randomize each delay between half and all of the backoff
+    pub jitter: bool,
+    // also retry 500/502/504 and timeouts for requests that create state on the server,
+    // where the first attempt may already have been processed
+    pub retry_non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: true,
+            retry_non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    // 429 and 503 mean the request was turned away unprocessed, so they are always safe to resend.
+    pub fn should_retry_status(&self, status: StatusCode, idempotent: bool) -> bool {
+        match status.as_u16() {
+            429 | 503 => true,
+            500 | 502 | 504 => idempotent || self.retry_non_idempotent,
+            _ => false,
+        }
+    }
+
+    pub fn should_retry_error(&self, error: &reqwest::Error, idempotent: bool) -> bool {
+        if error.is_connect() {
+            return true;
+        }
+        error.is_timeout() && (idempotent || self.retry_non_idempotent)
+    }
+
+    // How long to wait before the given retry (1 for the first retry); a server hint
+    // takes precedence over the computed backoff but is still capped at max_backoff.
+    pub fn delay(&self, retry: u32, hint: Option<Duration>) -> Duration {
+        if let Some(hint) = hint {
+            return hint.min(self.max_backoff);
+        }
+
+        let exponent = retry.saturating_sub(1).min(32) as i32;
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let backoff = backoff.min(self.max_backoff.as_secs_f64());
+
+        if self.jitter && backoff > 0.0 {
+            Duration::from_secs_f64(rand::thread_rng().gen_range(backoff / 2.0..=backoff))
+        } else {
+            Duration::from_secs_f64(backoff)
+        }
+    }
+}
+
+// Reads the wait the server asked for from `retry-after-ms`, `retry-after` (seconds or an
+// HTTP date) or the `x-ratelimit-reset-*` headers, taking the longest when several are present.
+pub fn delay_from_headers(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+
+    // negative, infinite or overflowing values are ignored rather than trusted
+    let seconds = |value: f64| Duration::try_from_secs_f64(value).ok();
+
+    let retry_after_ms = header("retry-after-ms")
+        .and_then(|v| v.parse::<f64>().ok())
+        .and_then(|ms| seconds(ms / 1000.0));
+    let retry_after = header("retry-after").and_then(|v| match v.parse::<f64>() {
+        Ok(value) => seconds(value),
+        // a date already past means retry right away
+        Err(_) => httpdate::parse_http_date(v)
+            .ok()
+            .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default()),
+    });
+    let resets = ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+        .iter()
+        .map(|name| header(name).and_then(parse_reset_duration));
+
+    [retry_after_ms, retry_after].into_iter().chain(resets).flatten().max()
+}
+
+// Parses the durations used by the rate limit headers, e.g. "1s", "6m0s", "20ms" or "1h2m3.5s".
+pub fn parse_reset_duration(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
+    let mut parsed_any = false;
+
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+
+        let n: f64 = number.parse().ok()?;
+        number.clear();
+        let unit_secs = match c {
+            'h' => 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                0.001
+            }
+            'm' => 60.0,
+            's' => 1.0,
+            _ => return None,
+        };
+        total += n * unit_secs;
+        parsed_any = true;
+    }
+
+    if !number.is_empty() || !parsed_any {
+        return None;
+    }
+    Duration::try_from_secs_f64(total).ok()
+}
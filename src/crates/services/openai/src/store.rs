@@ -0,0 +1,188 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chat::Role;
+use crate::conversation::Conversation;
+use crate::error::OpenAiError;
+
+const TITLE_LENGTH: usize = 60;
+
+// A conversation saved to disk, with enough metadata to find it again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredConversation {
+    pub id: String,
+    pub title: String,
+    // seconds since the unix epoch
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub conversation: Conversation,
+}
+
+// What `list` returns: the metadata without the messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConversationInfo {
+    pub id: String,
+    pub title: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub messages: usize,
+}
+
+impl From<&StoredConversation> for ConversationInfo {
+    fn from(stored: &StoredConversation) -> Self {
+        ConversationInfo {
+            id: stored.id.clone(),
+            title: stored.title.clone(),
+            created_at: stored.created_at,
+            updated_at: stored.updated_at,
+            messages: stored.conversation.messages.len(),
+        }
+    }
+}
+
+// Saves conversations as one JSON file per id under a directory.
+#[derive(Debug, Clone)]
+pub struct ConversationStore {
+    dir: PathBuf,
+}
+
+impl ConversationStore {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> ConversationStore {
+        ConversationStore { dir: dir.into() }
+    }
+
+    // Uses OPENAI_CONVERSATIONS_DIR, falling back to ~/.local/share/openai/conversations.
+    pub fn from_env() -> ConversationStore {
+        let dir = match std::env::var_os("OPENAI_CONVERSATIONS_DIR") {
+            Some(dir) => PathBuf::from(dir),
+            None => {
+                let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+                home.join(".local/share/openai/conversations")
+            }
+        };
+        ConversationStore::new(dir)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // Gives a conversation a new id. Nothing is written until it is saved, so a conversation
+    // whose first reply failed leaves no file behind.
+    pub fn create(&self, conversation: Conversation) -> StoredConversation {
+        let now = now();
+        StoredConversation {
+            id: new_id(now),
+            title: String::new(),
+            created_at: now,
+            updated_at: now,
+            conversation,
+        }
+    }
+
+    // Writes the conversation, bumping updated_at and naming it after the first user message
+    // if it has no title yet.
+    pub fn save(&self, stored: &mut StoredConversation) -> Result<(), OpenAiError> {
+        let path = self.path(&stored.id)?;
+        stored.updated_at = now();
+        if stored.title.is_empty() {
+            stored.title = title_for(&stored.conversation);
+        }
+
+        fs::create_dir_all(&self.dir).map_err(OpenAiError::Storage)?;
+        let json = serde_json::to_string_pretty(stored).map_err(invalid_data)?;
+
+        // write next to the target and rename so a crash never leaves half a file behind
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, json).map_err(OpenAiError::Storage)?;
+        fs::rename(&tmp, &path).map_err(OpenAiError::Storage)
+    }
+
+    pub fn load(&self, id: &str) -> Result<StoredConversation, OpenAiError> {
+        let json = fs::read_to_string(self.path(id)?).map_err(OpenAiError::Storage)?;
+        serde_json::from_str(&json).map_err(invalid_data)
+    }
+
+    // Every saved conversation, most recently updated first. Unreadable files are skipped.
+    pub fn list(&self) -> Result<Vec<ConversationInfo>, OpenAiError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(OpenAiError::Storage(e)),
+        };
+
+        let mut conversations = vec![];
+        for entry in entries {
+            let path = entry.map_err(OpenAiError::Storage)?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let stored = fs::read_to_string(&path)
+                .ok()
+                .and_then(|json| serde_json::from_str::<StoredConversation>(&json).ok());
+            if let Some(stored) = stored {
+                conversations.push(ConversationInfo::from(&stored));
+            }
+        }
+
+        conversations.sort_by(|a, b| b.updated_at.cmp(&a.updated_at).then_with(|| b.id.cmp(&a.id)));
+        Ok(conversations)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), OpenAiError> {
+        fs::remove_file(self.path(id)?).map_err(OpenAiError::Storage)
+    }
+
+    // Ids become file names, so anything that could escape the directory is refused.
+    fn path(&self, id: &str) -> Result<PathBuf, OpenAiError> {
+        let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(OpenAiError::Storage(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid conversation id: {:?}", id),
+            )));
+        }
+        Ok(self.dir.join(format!("{}.json", id)))
+    }
+}
+
+// Seconds since the Unix epoch, the timestamp format of everything saved to disk.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+// Time first so ids sort by creation, then random bits so two made in the same second differ.
+fn new_id(now: u64) -> String {
+    format!("{:x}-{:08x}", now, rand::thread_rng().gen::<u32>())
+}
+
+fn title_for(conversation: &Conversation) -> String {
+    let first = conversation
+        .messages
+        .iter()
+        .find(|message| message.role == Role::User)
+        .map(|message| message.content.text());
+
+    match first {
+        Some(text) => {
+            let line = text.lines().next().unwrap_or_default().trim();
+            if line.chars().count() > TITLE_LENGTH {
+                format!("{}…", line.chars().take(TITLE_LENGTH).collect::<String>())
+            } else {
+                line.to_string()
+            }
+        }
+        None => String::new(),
+    }
+}
+
+fn invalid_data(e: serde_json::Error) -> OpenAiError {
+    OpenAiError::Storage(io::Error::new(io::ErrorKind::InvalidData, e))
+}
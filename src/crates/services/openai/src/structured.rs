@@ -0,0 +1,112 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::chat::{ChatModel, Message, Payload, PayloadBuilder};
+use crate::client::{default_client, OpenAiClient};
+use crate::error::OpenAiError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    // any valid JSON object; the prompt itself has to ask for JSON
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub schema: Value,
+    // strict mode needs every property required and `additionalProperties: false`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+impl JsonSchemaFormat {
+    pub fn new(name: &str, schema: Value) -> JsonSchemaFormat {
+        JsonSchemaFormat {
+            name: sanitize_name(name),
+            description: None,
+            schema,
+            strict: None,
+        }
+    }
+
+    // Derives the schema from the type's `schemars::JsonSchema` implementation.
+    #[cfg(feature = "schemars")]
+    pub fn for_type<T: schemars::JsonSchema>() -> JsonSchemaFormat {
+        let mut schema = serde_json::to_value(schemars::schema_for!(T)).unwrap_or_default();
+        if let Some(object) = schema.as_object_mut() {
+            object.remove("$schema");
+        }
+        JsonSchemaFormat::new(&T::schema_name(), schema)
+    }
+}
+
+// The API only accepts [a-zA-Z0-9_-] in schema names.
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .take(64)
+        .collect()
+}
+
+// Schema requests that don't name a model use this one, since the default gpt-3.5-turbo
+// doesn't support structured outputs.
+pub const DEFAULT_SCHEMA_MODEL: ChatModel = ChatModel::Gpt4oMini;
+
+impl OpenAiClient {
+    // Asks for JSON output (validated against the schema when one is given) and
+    // deserializes the answer into T.
+    pub async fn prompt_json<T: DeserializeOwned>(
+        &self,
+        text: String,
+        conversation: Vec<Message>,
+        schema: Option<JsonSchemaFormat>,
+    ) -> Result<T, OpenAiError> {
+        self.chat_json(Payload::builder().messages(conversation).user(text), schema).await
+    }
+
+    // Like prompt_json, with the model and other options taken from the builder.
+    pub async fn chat_json<T: DeserializeOwned>(
+        &self,
+        payload: PayloadBuilder,
+        schema: Option<JsonSchemaFormat>,
+    ) -> Result<T, OpenAiError> {
+        let payload = match schema {
+            Some(json_schema) => payload
+                .default_model(DEFAULT_SCHEMA_MODEL)
+                .response_format(ResponseFormat::JsonSchema { json_schema }),
+            None => payload.response_format(ResponseFormat::JsonObject),
+        };
+        let mut payload = payload.build()?;
+
+        let mentions_json = payload
+            .messages
+            .iter()
+            .any(|m| m.content.text().to_lowercase().contains("json"));
+        if payload.response_format == Some(ResponseFormat::JsonObject) && !mentions_json {
+            // JSON mode is rejected unless the messages ask for JSON
+            payload.messages.insert(0, Message::system("Respond with a single JSON object."));
+        }
+
+        let content = self.chat(payload).await?.into_message()?.content.text();
+        serde_json::from_str::<T>(&content).map_err(|error| OpenAiError::InvalidOutput { content, error })
+    }
+}
+
+pub async fn prompt_json<T: DeserializeOwned>(
+    text: String,
+    conversation: Vec<Message>,
+    schema: Option<JsonSchemaFormat>,
+) -> Result<T, OpenAiError> {
+    default_client()?.prompt_json(text, conversation, schema).await
+}
+
+pub async fn chat_json<T: DeserializeOwned>(payload: PayloadBuilder, schema: Option<JsonSchemaFormat>) -> Result<T, OpenAiError> {
+    default_client()?.chat_json(payload, schema).await
+}
@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::chat::{ChatModel, Message, Payload, PayloadBuilder};
+use crate::error::OpenAiError;
+
+// A reusable prompt with `{{name}}` placeholders, e.g. in TOML:
+//
+//   name = "summarize"
+//   description = "Summarize text in a few sentences"
+//   system = "You are a concise assistant."
+//   user = "Summarize in {{sentences}} sentences:\n\n{{input}}"
+//   [defaults]
+//   sentences = "three"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct PromptTemplate {
+    // taken from the file name when left out
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub system: Option<String>,
+    pub user: String,
+    // used for variables the caller doesn't pass
+    #[serde(default)]
+    pub defaults: HashMap<String, String>,
+    // the provider's default model when unset
+    #[serde(default)]
+    pub model: Option<ChatModel>,
+    #[serde(default)]
+    pub temperature: Option<f32>,
+}
+
+impl PromptTemplate {
+    pub fn from_toml(text: &str) -> Result<PromptTemplate, OpenAiError> {
+        toml::from_str(text).map_err(|e| OpenAiError::Config(format!("invalid template: {}", e)))
+    }
+
+    pub fn from_yaml(text: &str) -> Result<PromptTemplate, OpenAiError> {
+        serde_yaml::from_str(text).map_err(|e| OpenAiError::Config(format!("invalid template: {}", e)))
+    }
+
+    // Every placeholder in the system and user sections.
+    pub fn variables(&self) -> BTreeSet<String> {
+        let mut variables = BTreeSet::new();
+        for section in self.system.iter().chain(Some(&self.user)) {
+            for (_, name, _) in placeholders(section) {
+                variables.insert(name.to_string());
+            }
+        }
+        variables
+    }
+
+    // Variables the template needs that have no default.
+    pub fn required(&self) -> BTreeSet<String> {
+        self.variables()
+            .into_iter()
+            .filter(|name| !self.defaults.contains_key(name))
+            .collect()
+    }
+
+    // The system and user messages with every placeholder filled in. Fails listing all the
+    // missing variables at once, rather than sending a prompt with holes in it.
+    pub fn render(&self, vars: &HashMap<String, String>) -> Result<Vec<Message>, OpenAiError> {
+        let missing: Vec<String> = self
+            .required()
+            .into_iter()
+            .filter(|name| !vars.contains_key(name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(OpenAiError::MissingVariables {
+                template: self.name.clone(),
+                missing,
+            });
+        }
+
+        let lookup = |name: &str| vars.get(name).or_else(|| self.defaults.get(name)).cloned().unwrap_or_default();
+        let mut messages = vec![];
+        if let Some(system) = &self.system {
+            messages.push(Message::system(fill(system, lookup)));
+        }
+        messages.push(Message::user(fill(&self.user, lookup)));
+        Ok(messages)
+    }
+
+    // A payload builder with the rendered messages and the template's model settings.
+    pub fn payload(&self, vars: &HashMap<String, String>) -> Result<PayloadBuilder, OpenAiError> {
+        let mut builder = Payload::builder().messages(self.render(vars)?);
+        if let Some(model) = &self.model {
+            builder = builder.model(model.clone());
+        }
+        if let Some(temperature) = self.temperature {
+            builder = builder.temperature(temperature);
+        }
+        Ok(builder)
+    }
+}
+
+// (start, name, end) of each `{{ name }}` in the text.
+fn placeholders(text: &str) -> Vec<(usize, &str, usize)> {
+    let mut found = vec![];
+    let mut from = 0;
+    while let Some(open) = text[from..].find("{{").map(|i| from + i) {
+        let Some(close) = text[open + 2..].find("}}").map(|i| open + 2 + i) else {
+            break;
+        };
+        let name = text[open + 2..close].trim();
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if valid {
+            found.push((open, name, close + 2));
+        }
+        from = close + 2;
+    }
+    found
+}
+
+fn fill<F: Fn(&str) -> String>(text: &str, lookup: F) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (start, name, end) in placeholders(text) {
+        out.push_str(&text[last..start]);
+        out.push_str(&lookup(name));
+        last = end;
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+// Named templates: the built-in ones plus any loaded from files.
+#[derive(Debug, Clone, Default)]
+pub struct TemplateLibrary {
+    templates: BTreeMap<String, PromptTemplate>,
+}
+
+impl TemplateLibrary {
+    pub fn builtin() -> TemplateLibrary {
+        let mut library = TemplateLibrary::default();
+        library.insert(PromptTemplate {
+            name: "summarize".to_string(),
+            description: Some("Summarize text in a few sentences".to_string()),
+            system: Some("You are a concise assistant. Keep names, numbers and decisions.".to_string()),
+            user: "Summarize the following in {{sentences}} sentences:\n\n{{input}}".to_string(),
+            defaults: HashMap::from([("sentences".to_string(), "three".to_string())]),
+            temperature: Some(0.2),
+            ..Default::default()
+        });
+        library.insert(PromptTemplate {
+            name: "explain-error".to_string(),
+            description: Some("Explain an error message and suggest a fix".to_string()),
+            system: Some(
+                "You are a senior engineer helping a teammate. Explain what the error means, \
+                 its most likely cause, and how to fix it. Be brief."
+                    .to_string(),
+            ),
+            user: "I got this error{{context}}:\n\n{{input}}".to_string(),
+            defaults: HashMap::from([("context".to_string(), String::new())]),
+            temperature: Some(0.2),
+            ..Default::default()
+        });
+        library
+    }
+
+    // The built-ins, overridden and extended by the directory in OPENAI_TEMPLATES_DIR.
+    pub fn from_env() -> Result<TemplateLibrary, OpenAiError> {
+        let mut library = TemplateLibrary::builtin();
+        if let Some(dir) = env::var_os("OPENAI_TEMPLATES_DIR") {
+            library.load_dir(dir)?;
+        }
+        Ok(library)
+    }
+
+    // Loads every .toml, .yaml and .yml file in the directory; a file replaces any template
+    // of the same name.
+    pub fn load_dir<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), OpenAiError> {
+        let mut paths: Vec<_> = fs::read_dir(dir)
+            .map_err(OpenAiError::Storage)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, io::Error>>()
+            .map_err(OpenAiError::Storage)?;
+        paths.sort();
+
+        for path in paths {
+            let parse = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("toml") => PromptTemplate::from_toml,
+                Some("yaml" | "yml") => PromptTemplate::from_yaml,
+                _ => continue,
+            };
+            let text = fs::read_to_string(&path).map_err(OpenAiError::Storage)?;
+            let mut template = parse(&text).map_err(|e| match e {
+                OpenAiError::Config(msg) => OpenAiError::Config(format!("{}: {}", path.display(), msg)),
+                other => other,
+            })?;
+            if template.name.is_empty() {
+                template.name = path.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+            }
+            self.insert(template);
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, template: PromptTemplate) {
+        self.templates.insert(template.name.clone(), template);
+    }
+
+    pub fn get(&self, name: &str) -> Result<&PromptTemplate, OpenAiError> {
+        self.templates
+            .get(name)
+            .ok_or_else(|| OpenAiError::InvalidRequest(format!("no prompt template named {:?}", name)))
+    }
+
+    // Sorted by name.
+    pub fn list(&self) -> Vec<&PromptTemplate> {
+        self.templates.values().collect()
+    }
+}
@@ -0,0 +1,172 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::chat::{Message, PayloadBuilder};
+use crate::client::OpenAiClient;
+use crate::error::OpenAiError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // a JSON schema object describing the arguments
+    pub parameters: Value,
+}
+
+impl Tool {
+    pub fn function(name: &str, description: &str, parameters: Value) -> Tool {
+        Tool {
+            kind: "function".to_string(),
+            function: FunctionDefinition {
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                parameters,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolChoice {
+    None,
+    Auto,
+    Required,
+    Function(String),
+}
+
+impl Serialize for ToolChoice {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ToolChoice::None => serializer.serialize_str("none"),
+            ToolChoice::Auto => serializer.serialize_str("auto"),
+            ToolChoice::Required => serializer.serialize_str("required"),
+            ToolChoice::Function(name) => json!({ "type": "function", "function": { "name": name } }).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ToolChoice {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        match &value {
+            Value::String(s) if s == "none" => Ok(ToolChoice::None),
+            Value::String(s) if s == "auto" => Ok(ToolChoice::Auto),
+            Value::String(s) if s == "required" => Ok(ToolChoice::Required),
+            _ => value["function"]["name"]
+                .as_str()
+                .map(|name| ToolChoice::Function(name.to_string()))
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid tool_choice: {}", value))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FunctionCall {
+    pub name: String,
+    // JSON encoded by the model; not guaranteed to be valid
+    pub arguments: String,
+}
+
+impl FunctionCall {
+    pub fn parse_arguments<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_str(&self.arguments)
+    }
+}
+
+type Handler = Box<dyn Fn(Value) -> Result<String, String> + Send + Sync>;
+
+// Tool definitions paired with the Rust functions that answer them.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: Vec<Tool>,
+    handlers: HashMap<String, Handler>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> ToolRegistry {
+        ToolRegistry::default()
+    }
+
+    pub fn register<F>(&mut self, tool: Tool, handler: F)
+    where
+        F: Fn(Value) -> Result<String, String> + Send + Sync + 'static,
+    {
+        self.tools.retain(|t| t.function.name != tool.function.name);
+        self.handlers.insert(tool.function.name.clone(), Box::new(handler));
+        self.tools.push(tool);
+    }
+
+    pub fn tools(&self) -> &[Tool] {
+        &self.tools
+    }
+
+    // Runs the handler for a call and wraps its output as a `tool` reply. Failures are
+    // reported back to the model as the reply content so it can correct itself.
+    pub fn call(&self, call: &ToolCall) -> Message {
+        let result = match self.handlers.get(&call.function.name) {
+            None => Err(format!("unknown tool: {}", call.function.name)),
+            Some(handler) => call
+                .function
+                .parse_arguments::<Value>()
+                .map_err(|e| format!("invalid arguments: {}", e))
+                .and_then(handler),
+        };
+
+        let content = result.unwrap_or_else(|e| format!("error: {}", e));
+        Message::tool(&call.id, content)
+    }
+}
+
+impl OpenAiClient {
+    // Sends the payload with the registry's tools, runs every tool call the model makes
+    // and feeds the results back until it answers without calling a tool. Returns the
+    // messages added along the way; the last one is the final answer.
+    pub async fn run_tools(
+        &self,
+        payload: PayloadBuilder,
+        registry: &ToolRegistry,
+        max_rounds: usize,
+    ) -> Result<Vec<Message>, OpenAiError> {
+        let mut payload = payload.build()?;
+        // the API rejects an empty tools list, and tool_choice without tools
+        if !registry.tools().is_empty() {
+            payload.tools = Some(registry.tools().to_vec());
+            payload.tool_choice.get_or_insert(ToolChoice::Auto);
+        }
+        let start = payload.messages.len();
+
+        for _ in 0..max_rounds {
+            let message = self.chat(payload.clone()).await?.into_message()?;
+
+            let calls = message.tool_calls.clone().unwrap_or_default();
+            payload.messages.push(message);
+            if calls.is_empty() {
+                return Ok(payload.messages.split_off(start));
+            }
+
+            for call in &calls {
+                payload.messages.push(registry.call(call));
+            }
+        }
+
+        Err(OpenAiError::ToolRoundsExceeded(max_rounds))
+    }
+}
@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::error::OpenAiError;
+
+// USD per million tokens.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Price {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+// Prices by model name. Dated snapshots like `gpt-4o-2024-08-06` use the longest listed prefix.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PriceTable(pub HashMap<String, Price>);
+
+impl Default for PriceTable {
+    // List prices at the time of writing; override with set or OPENAI_PRICES_FILE when they change.
+    fn default() -> Self {
+        let prices = [
+            ("gpt-3.5-turbo", 0.5, 1.5),
+            ("gpt-3.5-turbo-instruct", 1.5, 2.0),
+            ("gpt-4", 30.0, 60.0),
+            ("gpt-4-turbo", 10.0, 30.0),
+            ("gpt-4o", 2.5, 10.0),
+            ("gpt-4o-mini", 0.15, 0.6),
+            ("babbage-002", 0.4, 0.4),
+            ("davinci-002", 2.0, 2.0),
+            ("text-embedding-3-small", 0.02, 0.0),
+            ("text-embedding-3-large", 0.13, 0.0),
+            ("text-embedding-ada-002", 0.1, 0.0),
+            ("claude-3-5-sonnet", 3.0, 15.0),
+            ("claude-3-5-haiku", 0.8, 4.0),
+            ("claude-3-opus", 15.0, 75.0),
+        ];
+        PriceTable(
+            prices
+                .into_iter()
+                .map(|(model, prompt, completion)| (model.to_string(), Price { prompt, completion }))
+                .collect(),
+        )
+    }
+}
+
+impl PriceTable {
+    pub fn set(&mut self, model: &str, price: Price) {
+        self.0.insert(model.to_string(), price);
+    }
+
+    pub fn price(&self, model: &str) -> Option<Price> {
+        if let Some(price) = self.0.get(model) {
+            return Some(*price);
+        }
+        self.0
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| *price)
+    }
+
+    // Unknown models cost nothing rather than failing the call that used them.
+    pub fn cost(&self, model: &str, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        match self.price(model) {
+            Some(price) => {
+                (prompt_tokens as f64 * price.prompt + completion_tokens as f64 * price.completion) / 1_000_000.0
+            }
+            None => 0.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct ModelUsage {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    // USD, priced when the call was made
+    pub cost: f64,
+}
+
+impl ModelUsage {
+    fn add(&mut self, other: &ModelUsage) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+    }
+}
+
+// One line of the usage file.
+#[derive(Serialize, Deserialize)]
+struct UsageRecord {
+    model: String,
+    #[serde(flatten)]
+    usage: ModelUsage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct UsageReport {
+    pub models: BTreeMap<String, ModelUsage>,
+    pub total: ModelUsage,
+}
+
+// Adds up tokens and cost per model. With a file, every call is appended to it as one JSON
+// line and the lines are summed on creation, so totals accumulate across runs and several
+// processes can share a file without overwriting each other's calls. A file that can't be
+// read as records is an error rather than a fresh start.
+#[derive(Debug, Default)]
+pub struct UsageTracker {
+    prices: PriceTable,
+    usage: Mutex<BTreeMap<String, ModelUsage>>,
+    file: Option<PathBuf>,
+}
+
+impl UsageTracker {
+    pub fn new(prices: PriceTable) -> UsageTracker {
+        UsageTracker {
+            prices,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_file<P: Into<PathBuf>>(prices: PriceTable, file: P) -> Result<UsageTracker, OpenAiError> {
+        let file = file.into();
+        let usage = match fs::read_to_string(&file) {
+            Ok(contents) => match serde_json::from_str::<BTreeMap<String, ModelUsage>>(&contents) {
+                // the totals object older versions rewrote on every call
+                Ok(totals) => {
+                    migrate(&file, &totals)?;
+                    totals
+                }
+                Err(_) => read_records(&file, &contents)?,
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(OpenAiError::Storage(e)),
+        };
+        Ok(UsageTracker {
+            prices,
+            usage: Mutex::new(usage),
+            file: Some(file),
+        })
+    }
+
+    // Prices from OPENAI_PRICES_FILE (a JSON object of model to price) on top of the defaults,
+    // persisted to OPENAI_USAGE_FILE if it is set.
+    pub fn from_env() -> Result<UsageTracker, OpenAiError> {
+        let mut prices = PriceTable::default();
+        if let Ok(path) = env::var("OPENAI_PRICES_FILE") {
+            let json = fs::read_to_string(&path).map_err(OpenAiError::Storage)?;
+            let overrides: HashMap<String, Price> = serde_json::from_str(&json)
+                .map_err(|e| OpenAiError::Config(format!("invalid price file {}: {}", path, e)))?;
+            prices.0.extend(overrides);
+        }
+
+        match env::var_os("OPENAI_USAGE_FILE") {
+            Some(file) => UsageTracker::with_file(prices, file),
+            None => Ok(UsageTracker::new(prices)),
+        }
+    }
+
+    pub fn prices(&self) -> &PriceTable {
+        &self.prices
+    }
+
+    pub fn record(&self, model: &str, prompt_tokens: u32, completion_tokens: u32) {
+        let call = ModelUsage {
+            requests: 1,
+            prompt_tokens: u64::from(prompt_tokens),
+            completion_tokens: u64::from(completion_tokens),
+            cost: self
+                .prices
+                .cost(model, u64::from(prompt_tokens), u64::from(completion_tokens)),
+        };
+
+        self.usage.lock().unwrap().entry(model.to_string()).or_default().add(&call);
+        // tracking must never fail the call it is tracking, so a failed write is dropped
+        let _ = self.append(model, call);
+    }
+
+    pub fn report(&self) -> UsageReport {
+        let models = self.usage.lock().unwrap().clone();
+        let mut total = ModelUsage::default();
+        for usage in models.values() {
+            total.add(usage);
+        }
+        UsageReport { models, total }
+    }
+
+    // Also empties the file, including calls other processes appended to it.
+    pub fn reset(&self) -> Result<(), OpenAiError> {
+        self.usage.lock().unwrap().clear();
+        match &self.file {
+            Some(file) => fs::write(file, "").map_err(OpenAiError::Storage),
+            None => Ok(()),
+        }
+    }
+
+    fn append(&self, model: &str, usage: ModelUsage) -> Result<(), OpenAiError> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir).map_err(OpenAiError::Storage)?;
+        }
+        let record = UsageRecord {
+            model: model.to_string(),
+            usage,
+        };
+        let line = serde_json::to_string(&record).map_err(invalid_data)? + "\n";
+        // a single append-mode write, so lines from concurrent writers don't interleave
+        let mut out = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file)
+            .map_err(OpenAiError::Storage)?;
+        out.write_all(line.as_bytes()).map_err(OpenAiError::Storage)
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> OpenAiError {
+    OpenAiError::Storage(io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+fn read_records(file: &Path, contents: &str) -> Result<BTreeMap<String, ModelUsage>, OpenAiError> {
+    let mut usage = BTreeMap::<String, ModelUsage>::new();
+    for (number, line) in contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let record = serde_json::from_str::<UsageRecord>(line)
+            .map_err(|e| invalid_data(format!("{} line {}: {}", file.display(), number + 1, e)))?;
+        usage.entry(record.model).or_default().add(&record.usage);
+    }
+    Ok(usage)
+}
+
+// Rewrites a totals object as one record per model, replacing the file in one step.
+fn migrate(file: &Path, totals: &BTreeMap<String, ModelUsage>) -> Result<(), OpenAiError> {
+    let mut lines = String::new();
+    for (model, usage) in totals {
+        let record = UsageRecord {
+            model: model.clone(),
+            usage: *usage,
+        };
+        lines += &(serde_json::to_string(&record).map_err(invalid_data)? + "\n");
+    }
+    let tmp = file.with_extension("tmp");
+    fs::write(&tmp, lines).map_err(OpenAiError::Storage)?;
+    fs::rename(&tmp, file).map_err(OpenAiError::Storage)
+}
+
+static TRACKER: OnceLock<UsageTracker> = OnceLock::new();
+
+// The tracker every client reports to. Configured from the environment on first use, falling
+// back to in-memory tracking with default prices if that configuration is broken.
+pub fn tracker() -> &'static UsageTracker {
+    TRACKER.get_or_init(|| UsageTracker::from_env().unwrap_or_default())
+}
+
+// Replaces the environment configuration; only possible before the first call is tracked.
+pub fn install(tracker: UsageTracker) -> Result<(), UsageTracker> {
+    TRACKER.set(tracker)
+}
+
+pub fn report() -> UsageReport {
+    tracker().report()
+}
@@ -1,75 +1,275 @@
 use nu_plugin::{serve_plugin, EvaluatedCall, LabeledError, MsgPackSerializer, Plugin};
-use nu_protocol::{PluginSignature, Type, Value};
+use nu_protocol::{PluginSignature, Record, Span, SyntaxShape, Type, Value};
+use std::collections::HashMap;
+use std::io::{self, Write};
 
-use openai::chat;
+use openai::chat::{self, Role};
+use openai::conversation::Conversation;
+use openai::provider::{self, Backend};
+use openai::store::{ConversationInfo, ConversationStore};
+use openai::templates::TemplateLibrary;
+use openai::usage::{self, ModelUsage};
+use openai::OpenAiError;
 
 struct LLM;
 
 impl LLM {
     fn print_message(msg: &chat::Message) {
-        let emoji = match msg.role.as_str() {
-            "assistant" => "🤖",
-            "user" => "👤",
-            "system" => "🖥️",
-            _ => "👽",
+        let emoji = match msg.role {
+            Role::Assistant => "🤖",
+            Role::User => "👤",
+            Role::System => "🖥️",
+            Role::Tool => "🔧",
         };
-        println!("{} {} says: {}", emoji, msg.role, msg.content)
+        eprintln!("{} {} says: {}", emoji, msg.role, msg.content)
+    }
+
+    // --service when given, otherwise LLM_PROVIDER, then openai
+    fn backend(call: &EvaluatedCall) -> Result<Backend, LabeledError> {
+        match call.get_flag::<String>("service")? {
+            Some(name) => name.parse::<Backend>().map_err(OpenAiError::Config),
+            None => Backend::from_env(),
+        }
+        .map_err(|error| Self::labeled(error, call.head))
     }
 
     fn prompt(&self, call: &EvaluatedCall) -> Result<Value, LabeledError> {
         // parse inputs
-        // service enum Service("openai", "gemini", "local"), default to openai
-        // model depends on Service, default to "gpt4-turbo" for openai
+        // service is --service (openai, local or anthropic), default to LLM_PROVIDER, then openai
+        // model depends on the service, see provider::Backend
         // prompt is a string, required
         // temperature is a float, default to 0.1
         // max_tokens is an int, default to 1000. if flag "short" is set, default to 150.
         //  if flag "medium" is set, default to 500. if flag "long" is set, default to 2000.
 
         let user_msg: String = call.req(0)?;
-        let conversation = vec![] as Vec<chat::Message>;
+        let temperature = call.get_flag::<f64>("temperature")?.unwrap_or(0.1);
+        let max_tokens = match call.get_flag::<i64>("max-tokens")? {
+            Some(max_tokens) => max_tokens,
+            None if call.has_flag("short") => 150,
+            None if call.has_flag("medium") => 500,
+            None if call.has_flag("long") => 2000,
+            None => 1000,
+        };
+        let backend = Self::backend(call)?;
+
+        // every prompt is saved so it can be picked up again with --conversation <id>
+        let store = ConversationStore::from_env();
+        let mut stored = match call.get_flag::<String>("conversation")? {
+            Some(id) => store.load(&id),
+            None => backend
+                .provider()
+                .map(|provider| store.create(Conversation::new(provider.default_model(), None))),
+        }
+        .map_err(|error| Self::labeled(error, call.head))?;
+        // only what's sent is trimmed to the budget; the saved history keeps every turn
+        let mut context = stored.conversation.clone();
+        context.push(chat::Message::user(user_msg.clone()));
+        context.trim();
+        let payload = chat::Payload::builder()
+            .messages(context.to_messages())
+            .temperature(temperature as f32)
+            .max_tokens(i32::try_from(max_tokens).unwrap_or(i32::MAX));
+
+        Self::print_message(&chat::Message::user(user_msg.clone()));
 
-        Self::print_message(&chat::Message {
-            role: "user".to_string(),
-            content: user_msg.clone(),
+        // stream tokens to the terminal as they arrive instead of waiting for the full answer,
+        // on stderr since stdout carries the plugin protocol
+        eprint!("🤖 assistant says: ");
+        let response = provider::chat_stream_sync(backend, payload, |token| {
+            eprint!("{}", token);
+            let _ = io::stderr().flush();
         });
+        eprintln!();
 
-        // turning an async function into a blocking function
-        let response = futures::executor::block_on(
-            chat::prompt(user_msg, conversation)
-        );
+        let msg = response.map_err(|error| Self::labeled(error, call.head))?;
 
-        match response {
-            Ok(msg) => {
-                Self::print_message(&msg);
-                Ok(Value::String { val: msg.content, internal_span: call.head })
-            },
-            Err(error) => Err(LabeledError {
-                label: "Error".into(),
-                msg: format!("Error: {}", error),
-                span: Some(call.head),
-            }),
+        stored.conversation.push(chat::Message::user(user_msg));
+        stored.conversation.push(msg.clone());
+        store.save(&mut stored).map_err(|error| Self::labeled(error, call.head))?;
+        eprintln!("💾 continue with: prompt --conversation {}", stored.id);
+
+        Ok(Value::String { val: msg.content.to_string(), internal_span: call.head })
+    }
+
+    // Piped-in text becomes the template's `input` variable; a record argument sets the rest.
+    fn template(&self, call: &EvaluatedCall, input: &Value) -> Result<Value, LabeledError> {
+        let name: String = call.req(0)?;
+        let mut vars = HashMap::new();
+        if let Ok(text) = input.as_string() {
+            vars.insert(String::from("input"), text);
+        }
+        if let Some(Value::Record { val, .. }) = call.opt::<Value>(1)? {
+            for (key, value) in val.iter() {
+                vars.insert(key.clone(), value.as_string()?);
+            }
+        }
+
+        let backend = Self::backend(call)?;
+        let payload = TemplateLibrary::from_env()
+            .and_then(|library| library.get(&name)?.payload(&vars))
+            .map_err(|error| Self::labeled(error, call.head))?;
+
+        eprint!("🤖 assistant says: ");
+        let response = provider::chat_stream_sync(backend, payload, |token| {
+            eprint!("{}", token);
+            let _ = io::stderr().flush();
+        });
+        eprintln!();
+
+        let msg = response.map_err(|error| Self::labeled(error, call.head))?;
+        Ok(Value::String { val: msg.content.to_string(), internal_span: call.head })
+    }
+
+    fn templates(&self, call: &EvaluatedCall) -> Result<Value, LabeledError> {
+        let library = TemplateLibrary::from_env().map_err(|error| Self::labeled(error, call.head))?;
+        let rows = library
+            .list()
+            .into_iter()
+            .map(|template| {
+                let mut record = Record::new();
+                record.push("name", Value::String { val: template.name.clone(), internal_span: call.head });
+                record.push(
+                    "description",
+                    Value::String { val: template.description.clone().unwrap_or_default(), internal_span: call.head },
+                );
+                record.push(
+                    "variables",
+                    Value::String {
+                        val: template.variables().into_iter().collect::<Vec<_>>().join(", "),
+                        internal_span: call.head,
+                    },
+                );
+                Value::Record { val: record, internal_span: call.head }
+            })
+            .collect();
+
+        Ok(Value::List { vals: rows, internal_span: call.head })
+    }
+
+    fn conversations(&self, call: &EvaluatedCall) -> Result<Value, LabeledError> {
+        let conversations = ConversationStore::from_env()
+            .list()
+            .map_err(|error| Self::labeled(error, call.head))?;
+
+        Ok(Value::List {
+            vals: conversations.iter().map(|info| Self::info_record(info, call.head)).collect(),
+            internal_span: call.head,
+        })
+    }
+
+    fn forget(&self, call: &EvaluatedCall) -> Result<Value, LabeledError> {
+        let id: String = call.req(0)?;
+        ConversationStore::from_env()
+            .delete(&id)
+            .map_err(|error| Self::labeled(error, call.head))?;
+
+        Ok(Value::Nothing { internal_span: call.head })
+    }
+
+    fn usage(&self, call: &EvaluatedCall) -> Result<Value, LabeledError> {
+        let report = usage::report();
+        let mut rows: Vec<Value> = report
+            .models
+            .iter()
+            .map(|(model, usage)| Self::usage_record(model, usage, call.head))
+            .collect();
+        rows.push(Self::usage_record("total", &report.total, call.head));
+
+        Ok(Value::List { vals: rows, internal_span: call.head })
+    }
+
+    fn usage_record(model: &str, usage: &ModelUsage, span: Span) -> Value {
+        let mut record = Record::new();
+        record.push("model", Value::String { val: model.to_string(), internal_span: span });
+        record.push("requests", Value::Int { val: usage.requests as i64, internal_span: span });
+        record.push("prompt_tokens", Value::Int { val: usage.prompt_tokens as i64, internal_span: span });
+        record.push("completion_tokens", Value::Int { val: usage.completion_tokens as i64, internal_span: span });
+        record.push("cost_usd", Value::Float { val: usage.cost, internal_span: span });
+        Value::Record { val: record, internal_span: span }
+    }
+
+    fn info_record(info: &ConversationInfo, span: Span) -> Value {
+        let mut record = Record::new();
+        record.push("id", Value::String { val: info.id.clone(), internal_span: span });
+        record.push("title", Value::String { val: info.title.clone(), internal_span: span });
+        record.push("messages", Value::Int { val: info.messages as i64, internal_span: span });
+        record.push("created_at", Value::Int { val: info.created_at as i64, internal_span: span });
+        record.push("updated_at", Value::Int { val: info.updated_at as i64, internal_span: span });
+        Value::Record { val: record, internal_span: span }
+    }
+
+    fn labeled(error: OpenAiError, span: Span) -> LabeledError {
+        let label = match &error {
+            OpenAiError::MissingApiKey => "Missing API key",
+            OpenAiError::Config(_) => "Configuration error",
+            OpenAiError::InvalidRequest(_) => "Invalid option",
+            OpenAiError::MissingVariables { .. } => "Missing template variables",
+            OpenAiError::RateLimited { .. } => "Rate limited",
+            OpenAiError::Api { .. } => "API error",
+            OpenAiError::Transport(_) => "Network error",
+            OpenAiError::Storage(_) => "Conversation storage error",
+            _ => "Error",
+        };
+        LabeledError {
+            label: label.into(),
+            msg: format!("Error: {}", error),
+            span: Some(span),
         }
     }
 }
 
 impl Plugin for LLM {
     fn signature(&self) -> Vec<PluginSignature> {
-        vec![PluginSignature::build("prompt")
-            .usage("text prompt")
-            .input_output_type(Type::String, Type::String)]
+        vec![
+            PluginSignature::build("prompt")
+                .usage("text prompt")
+                .named("conversation", SyntaxShape::String, "id of a saved conversation to continue", Some('c'))
+                .named("service", SyntaxShape::String, "openai, local or anthropic", Some('s'))
+                .named("temperature", SyntaxShape::Number, "sampling temperature, 0 to 2 (default 0.1)", Some('t'))
+                .named("max-tokens", SyntaxShape::Int, "longest answer in tokens (default 1000)", Some('m'))
+                .switch("short", "answer in at most 150 tokens", None)
+                .switch("medium", "answer in at most 500 tokens", None)
+                .switch("long", "answer in up to 2000 tokens", None)
+                .input_output_type(Type::String, Type::String),
+            PluginSignature::build("prompt conversations")
+                .usage("list saved conversations, most recent first")
+                .input_output_type(Type::Nothing, Type::Table(vec![])),
+            PluginSignature::build("prompt template")
+                .usage("run a named prompt template, with piped-in text as its input variable")
+                .required("name", SyntaxShape::String, "template name, see `prompt templates`")
+                .optional("variables", SyntaxShape::Record(vec![]), "values for the template's placeholders")
+                .named("service", SyntaxShape::String, "openai, local or anthropic", Some('s'))
+                .input_output_types(vec![(Type::String, Type::String), (Type::Nothing, Type::String)]),
+            PluginSignature::build("prompt templates")
+                .usage("list the available prompt templates")
+                .input_output_type(Type::Nothing, Type::Table(vec![])),
+            PluginSignature::build("prompt usage")
+                .usage("tokens and dollars spent so far, per model")
+                .input_output_type(Type::Nothing, Type::Table(vec![])),
+            PluginSignature::build("prompt forget")
+                .usage("delete a saved conversation")
+                .required("id", SyntaxShape::String, "conversation id")
+                .input_output_type(Type::Nothing, Type::Nothing),
+        ]
     }
 
     fn run(
         &mut self,
         name: &str,
         call: &EvaluatedCall,
-        _input: &Value,
+        input: &Value,
     ) -> Result<Value, LabeledError> {
         match name {
             "prompt" => {
                 let result = self.prompt(call);
                 result
             },
+            "prompt conversations" => self.conversations(call),
+            "prompt forget" => self.forget(call),
+            "prompt usage" => self.usage(call),
+            "prompt template" => self.template(call, input),
+            "prompt templates" => self.templates(call),
             _ => Err(LabeledError {
                 label: "Unknown command".into(),
                 msg: "Unknown command".into(),
@@ -80,5 +280,60 @@ impl Plugin for LLM {
 }
 
 fn main() {
+    // each command runs in a fresh plugin process, so usage has to live in a file to add up
+    if std::env::var_os("OPENAI_USAGE_FILE").is_none() {
+        if let Some(home) = std::env::var_os("HOME") {
+            let file = std::path::PathBuf::from(home).join(".local/share/openai/usage.json");
+            std::env::set_var("OPENAI_USAGE_FILE", file);
+        }
+    }
     serve_plugin(&mut LLM {}, MsgPackSerializer {})
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nu_protocol::Spanned;
+    use openai::mock::{MockResponse, MockServer, TempPath};
+
+    #[test]
+    fn prompt_returns_the_streamed_reply() {
+        let server = MockServer::start();
+        server.enqueue(MockResponse::chat_stream(&["PO", "LO!"]));
+        std::env::set_var("OPENAI_BASE_URL", server.url());
+        std::env::set_var("OPENAI_API_KEY", "sk-mock");
+        let dir = TempPath::new("nu-plugin-llm");
+        std::env::set_var("OPENAI_CONVERSATIONS_DIR", dir.as_os_str());
+
+        let call = EvaluatedCall {
+            head: Span::test_data(),
+            positional: vec![Value::test_string("MARCO!")],
+            named: vec![],
+        };
+
+        let result = LLM.prompt(&call).unwrap();
+        assert_eq!(result.as_string().unwrap(), "POLO!");
+
+        // the exchange was saved and is sent again when the conversation continues
+        let saved = ConversationStore::from_env().list().unwrap();
+        assert_eq!(saved[0].messages, 2);
+
+        server.enqueue(MockResponse::chat_stream(&["Still ", "here."]));
+        let call = EvaluatedCall {
+            head: Span::test_data(),
+            positional: vec![Value::test_string("Are you there?")],
+            named: vec![(
+                Spanned { item: String::from("conversation"), span: Span::test_data() },
+                Some(Value::test_string(saved[0].id.clone())),
+            )],
+        };
+        LLM.prompt(&call).unwrap();
+
+        let body = server.requests()[1].json();
+        assert_eq!(body["max_tokens"], 1000);
+        let history = body["messages"].clone();
+        assert_eq!(history[0]["content"], "MARCO!");
+        assert_eq!(history[1]["content"], "POLO!");
+        assert_eq!(history[2]["content"], "Are you there?");
+    }
+}